@@ -1,41 +1,127 @@
 #[cfg(test)]
 mod acp_tests;
+#[cfg(feature = "process")]
+mod process;
 mod schema;
+mod workspace;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use futures::{
-    AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, FutureExt as _,
+    AsyncBufReadExt as _, AsyncRead, AsyncWrite, AsyncWriteExt as _, FutureExt as _, Stream,
     StreamExt as _,
     channel::{
         mpsc::{self, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
-    future::LocalBoxFuture,
+    future::{self, AbortHandle, Abortable, Aborted, Either, FusedFuture as _, Shared},
     io::BufReader,
     select_biased,
+    stream::FuturesUnordered,
 };
+use futures_timer::Delay;
 use parking_lot::Mutex;
+#[cfg(feature = "process")]
+pub use process::*;
 pub use schema::*;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
     sync::{
         Arc,
-        atomic::{AtomicI32, Ordering::SeqCst},
+        atomic::{AtomicI64, Ordering::SeqCst},
     },
+    time::Duration,
 };
+pub use workspace::*;
 
 /// A connection to a separate agent process over the ACP protocol.
-pub struct AgentConnection(Connection<AnyClientRequest, AnyAgentRequest>);
+///
+/// Cloning the connection is cheap, and all clones share the same underlying
+/// connection.
+#[derive(Clone)]
+pub struct AgentConnection(Arc<Connection<AnyClientRequest, AnyAgentRequest>>);
 
 /// A connection to a separate client process over the ACP protocol.
-pub struct ClientConnection(Connection<AnyAgentRequest, AnyClientRequest>);
+///
+/// Cloning the connection is cheap, and all clones share the same underlying
+/// connection.
+#[derive(Clone)]
+pub struct ClientConnection(Arc<Connection<AnyAgentRequest, AnyClientRequest>>);
+
+/// Passed to every handler method, describing the message being handled.
+pub struct HandlerContext<P> {
+    request_id: Option<RequestId>,
+    peer: P,
+    cancellation: CancellationSignal,
+}
+
+impl<P> HandlerContext<P> {
+    /// The id of the request being handled, or `None` for notifications.
+    pub fn request_id(&self) -> Option<&RequestId> {
+        self.request_id.as_ref()
+    }
+
+    /// The connection to the peer that sent the message, which can be used
+    /// to send requests and notifications back while handling it.
+    pub fn peer(&self) -> &P {
+        &self.peer
+    }
+
+    /// Fires when the peer cancels the request being handled. The handler
+    /// can then wrap up and respond, e.g. with a partial result. If it is
+    /// still running once the connection's cancellation grace period has
+    /// passed, its future is dropped and the peer gets a cancellation error.
+    pub fn cancellation(&self) -> &CancellationSignal {
+        &self.cancellation
+    }
+
+    fn map_peer<Q>(self, f: impl FnOnce(P) -> Q) -> HandlerContext<Q> {
+        HandlerContext {
+            request_id: self.request_id,
+            peer: f(self.peer),
+            cancellation: self.cancellation,
+        }
+    }
+}
+
+/// Signals that the peer has cancelled a request with `$/cancelRequest`.
+#[derive(Clone)]
+pub struct CancellationSignal(Option<Shared<oneshot::Receiver<()>>>);
+
+impl CancellationSignal {
+    fn new() -> (oneshot::Sender<()>, Self) {
+        let (tx, rx) = oneshot::channel();
+        (tx, Self(Some(rx.shared())))
+    }
+
+    /// A signal that never fires, used for notifications.
+    fn never() -> Self {
+        Self(None)
+    }
+
+    /// Whether the request has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0
+            .as_ref()
+            .is_some_and(|rx| matches!(rx.clone().now_or_never(), Some(Ok(()))))
+    }
+
+    /// Resolves once the request is cancelled. Never resolves if the request
+    /// completes without being cancelled.
+    pub async fn cancelled(&self) {
+        match &self.0 {
+            Some(rx) if rx.clone().await.is_ok() => {}
+            _ => future::pending().await,
+        }
+    }
+}
 
 impl AgentConnection {
     /// Connect to an agent process, handling any incoming requests
     /// using the given handler.
-    pub fn connect_to_agent<H: 'static + Client>(
+    pub fn connect_to_agent<H: 'static + Client + MaybeSendSync>(
         handler: H,
         outgoing_bytes: impl Unpin + AsyncWrite,
         incoming_bytes: impl Unpin + AsyncRead,
@@ -46,9 +132,20 @@ impl AgentConnection {
     ) {
         let handler = Arc::new(handler);
         let (connection, handler_task, io_task) = Connection::new(
-            Box::new(move |request| {
+            Box::new({
+                let handler = handler.clone();
+                move |request, cx| {
+                    let handler = handler.clone();
+                    boxed(async move { handler.call(request, cx.map_peer(Self)).await })
+                }
+            }),
+            Box::new(move |notification, cx| {
                 let handler = handler.clone();
-                async move { handler.call(request).await }.boxed_local()
+                boxed(async move {
+                    handler
+                        .call_notification(notification, cx.map_peer(Self))
+                        .await
+                })
             }),
             outgoing_bytes,
             incoming_bytes,
@@ -57,24 +154,94 @@ impl AgentConnection {
     }
 
     /// Send a request to the agent and wait for a response.
+    ///
+    /// Dropping the returned future before it resolves cancels the request,
+    /// notifying the agent with `$/cancelRequest`.
     pub fn request<R: AgentRequest + 'static>(
         &self,
         params: R,
-    ) -> impl Future<Output = Result<R::Response, crate::Error>> {
+    ) -> impl use<R> + Future<Output = Result<R::Response, crate::Error>> {
         let params = params.into_any();
         let result = self.0.request(params.method_name(), params);
         async move {
             let result = result.await?;
-            R::response_from_any(result).ok_or_else(|| crate::Error {
-                code: -32700,
-                message: "Unexpected Response".to_string(),
-            })
+            R::response_from_any(result)
+                .ok_or_else(|| crate::Error::parse_error("Unexpected Response"))
+        }
+    }
+
+    /// Like [`Self::request`], but fails with [`Error::REQUEST_TIMED_OUT`]
+    /// if no response arrives within `timeout`, regardless of the timeouts
+    /// configured on this connection.
+    pub fn request_with_timeout<R: AgentRequest + 'static>(
+        &self,
+        params: R,
+        timeout: Duration,
+    ) -> impl use<R> + Future<Output = Result<R::Response, crate::Error>> {
+        let params = params.into_any();
+        let result = self
+            .0
+            .request_with_timeout(params.method_name(), params, Some(timeout));
+        async move {
+            let result = result.await?;
+            R::response_from_any(result)
+                .ok_or_else(|| crate::Error::parse_error("Unexpected Response"))
         }
     }
+
+    /// Set how long requests to the agent may wait for a response before
+    /// failing with [`Error::REQUEST_TIMED_OUT`]. Pass `None` to wait
+    /// indefinitely, which is the default.
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        self.0.set_default_timeout(timeout);
+    }
+
+    /// Override the default timeout for requests of the given type. Pass
+    /// `None` to never time out requests of this type.
+    pub fn set_timeout<R: AgentRequest>(&self, timeout: Option<Duration>) {
+        self.0.set_timeout(R::METHOD_NAME, timeout);
+    }
+
+    /// Limit how many requests of the given type from the agent are handled
+    /// at once. Requests beyond the limit wait until an earlier one finishes.
+    /// Pass `None` to remove the limit.
+    pub fn set_concurrency_limit<R: ClientRequest>(&self, limit: Option<usize>) {
+        self.0.set_concurrency_limit(R::METHOD_NAME, limit);
+    }
+
+    /// Set how long a handler may keep running after the agent cancels its
+    /// request, before it is aborted and the agent gets a cancellation
+    /// error. Defaults to [`DEFAULT_CANCELLATION_GRACE_PERIOD`]. Pass `None`
+    /// to always let handlers finish.
+    pub fn set_cancellation_grace_period(&self, grace_period: Option<Duration>) {
+        *self.0.cancellation_grace_period.lock() = grace_period;
+    }
+
+    /// Send a notification to the agent. No response is expected.
+    pub fn notify<N: AgentNotification>(&self, params: N) -> Result<(), crate::Error> {
+        self.0.notify(params)
+    }
+
+    /// The current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.0.state()
+    }
+
+    /// A stream that yields the current state of the connection, followed by
+    /// every subsequent change.
+    pub fn watch_state(&self) -> impl Stream<Item = ConnectionState> + use<> {
+        self.0.watch_state()
+    }
+
+    /// Stop sending requests, write any messages that are already queued and
+    /// then close the connection.
+    pub fn close(&self) {
+        self.0.close()
+    }
 }
 
 impl ClientConnection {
-    pub fn connect_to_client<H: 'static + Agent>(
+    pub fn connect_to_client<H: 'static + Agent + MaybeSendSync>(
         handler: H,
         outgoing_bytes: impl Unpin + AsyncWrite,
         incoming_bytes: impl Unpin + AsyncRead,
@@ -85,9 +252,20 @@ impl ClientConnection {
     ) {
         let handler = Arc::new(handler);
         let (connection, handler_task, io_task) = Connection::new(
-            Box::new(move |request| {
+            Box::new({
                 let handler = handler.clone();
-                async move { handler.call(request).await }.boxed_local()
+                move |request, cx| {
+                    let handler = handler.clone();
+                    boxed(async move { handler.call(request, cx.map_peer(Self)).await })
+                }
+            }),
+            Box::new(move |notification, cx| {
+                let handler = handler.clone();
+                boxed(async move {
+                    handler
+                        .call_notification(notification, cx.map_peer(Self))
+                        .await
+                })
             }),
             outgoing_bytes,
             incoming_bytes,
@@ -95,6 +273,10 @@ impl ClientConnection {
         (Self(connection), handler_task, io_task)
     }
 
+    /// Send a request to the client and wait for a response.
+    ///
+    /// Dropping the returned future before it resolves cancels the request,
+    /// notifying the client with `$/cancelRequest`.
     pub fn request<R: ClientRequest>(
         &self,
         params: R,
@@ -103,12 +285,77 @@ impl ClientConnection {
         let result = self.0.request(params.method_name(), params);
         async move {
             let result = result.await?;
-            R::response_from_any(result).ok_or_else(|| Error {
-                code: -32700,
-                message: "Could not parse".to_string(),
-            })
+            R::response_from_any(result).ok_or_else(|| Error::parse_error("Could not parse"))
         }
     }
+
+    /// Like [`Self::request`], but fails with [`Error::REQUEST_TIMED_OUT`]
+    /// if no response arrives within `timeout`, regardless of the timeouts
+    /// configured on this connection.
+    pub fn request_with_timeout<R: ClientRequest>(
+        &self,
+        params: R,
+        timeout: Duration,
+    ) -> impl use<R> + Future<Output = Result<R::Response, crate::Error>> {
+        let params = params.into_any();
+        let result = self
+            .0
+            .request_with_timeout(params.method_name(), params, Some(timeout));
+        async move {
+            let result = result.await?;
+            R::response_from_any(result).ok_or_else(|| Error::parse_error("Could not parse"))
+        }
+    }
+
+    /// Set how long requests to the client may wait for a response before
+    /// failing with [`Error::REQUEST_TIMED_OUT`]. Pass `None` to wait
+    /// indefinitely, which is the default.
+    pub fn set_default_timeout(&self, timeout: Option<Duration>) {
+        self.0.set_default_timeout(timeout);
+    }
+
+    /// Override the default timeout for requests of the given type. Pass
+    /// `None` to never time out requests of this type.
+    pub fn set_timeout<R: ClientRequest>(&self, timeout: Option<Duration>) {
+        self.0.set_timeout(R::METHOD_NAME, timeout);
+    }
+
+    /// Limit how many requests of the given type from the client are handled
+    /// at once. Requests beyond the limit wait until an earlier one finishes.
+    /// Pass `None` to remove the limit.
+    pub fn set_concurrency_limit<R: AgentRequest>(&self, limit: Option<usize>) {
+        self.0.set_concurrency_limit(R::METHOD_NAME, limit);
+    }
+
+    /// Set how long a handler may keep running after the client cancels its
+    /// request, before it is aborted and the client gets a cancellation
+    /// error. Defaults to [`DEFAULT_CANCELLATION_GRACE_PERIOD`]. Pass `None`
+    /// to always let handlers finish.
+    pub fn set_cancellation_grace_period(&self, grace_period: Option<Duration>) {
+        *self.0.cancellation_grace_period.lock() = grace_period;
+    }
+
+    /// Send a notification to the client. No response is expected.
+    pub fn notify<N: ClientNotification>(&self, params: N) -> Result<(), crate::Error> {
+        self.0.notify(params)
+    }
+
+    /// The current state of the connection.
+    pub fn state(&self) -> ConnectionState {
+        self.0.state()
+    }
+
+    /// A stream that yields the current state of the connection, followed by
+    /// every subsequent change.
+    pub fn watch_state(&self) -> impl Stream<Item = ConnectionState> + use<> {
+        self.0.watch_state()
+    }
+
+    /// Stop sending requests, write any messages that are already queued and
+    /// then close the connection.
+    pub fn close(&self) {
+        self.0.close()
+    }
 }
 
 struct Connection<In, Out>
@@ -116,37 +363,240 @@ where
     In: AnyRequest,
     Out: AnyRequest,
 {
-    outgoing_tx: UnboundedSender<OutgoingMessage<Out, In::Response>>,
+    outgoing_tx: UnboundedSender<OutgoingMessage<Out, Out::Notification, In::Response>>,
     response_senders: ResponseSenders<Out::Response>,
-    next_id: AtomicI32,
+    concurrency_limits: ConcurrencyLimits,
+    timeouts: Mutex<RequestTimeouts>,
+    cancellation_grace_period: Mutex<Option<Duration>>,
+    state: SharedState,
+    close_tx: Mutex<Option<oneshot::Sender<()>>>,
+    next_id: AtomicI64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    /// [`AgentConnection::close`] or [`ClientConnection::close`] was called,
+    /// and queued messages are still being written.
+    Closing,
+    Closed(CloseReason),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection was closed on this side.
+    Closed,
+    /// The peer closed its end of the connection, e.g. because it exited.
+    PeerDisconnected,
+    /// Reading or writing a message failed.
+    Error(String),
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Closed => write!(f, "connection closed"),
+            CloseReason::PeerDisconnected => write!(f, "peer disconnected"),
+            CloseReason::Error(error) => write!(f, "{error}"),
+        }
+    }
+}
+
+type SharedState = Arc<Mutex<StateWatch>>;
+
+struct StateWatch {
+    state: ConnectionState,
+    subscribers: Vec<UnboundedSender<ConnectionState>>,
+}
+
+impl Default for StateWatch {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Connected,
+            subscribers: Vec::new(),
+        }
+    }
+}
+
+impl StateWatch {
+    fn set(&mut self, state: ConnectionState) {
+        self.subscribers
+            .retain(|tx| tx.unbounded_send(state.clone()).is_ok());
+        self.state = state;
+    }
+
+    /// The error for requests that can't complete because the connection is
+    /// not, or no longer, connected.
+    fn connection_closed_error(&self) -> Error {
+        match &self.state {
+            ConnectionState::Closed(reason) => Error::connection_closed(reason),
+            ConnectionState::Connected | ConnectionState::Closing => {
+                Error::connection_closed(CloseReason::Closed)
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RequestTimeouts {
+    default: Option<Duration>,
+    methods: HashMap<&'static str, Option<Duration>>,
 }
 
 type ResponseSenders<T> =
-    Arc<Mutex<HashMap<i32, (&'static str, oneshot::Sender<Result<T, crate::Error>>)>>>;
+    Arc<Mutex<HashMap<RequestId, (&'static str, oneshot::Sender<Result<T, crate::Error>>)>>>;
+
+type ConcurrencyLimits = Arc<Mutex<HashMap<&'static str, usize>>>;
+
+/// Implemented by every type, unless the `send` feature is enabled, in which
+/// case it requires `Send + Sync`. Handlers passed to
+/// [`AgentConnection::connect_to_agent`] and
+/// [`ClientConnection::connect_to_client`] must implement it.
+#[cfg(not(feature = "send"))]
+pub trait MaybeSendSync {}
+#[cfg(not(feature = "send"))]
+impl<T: ?Sized> MaybeSendSync for T {}
+
+#[cfg(feature = "send")]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(feature = "send")]
+impl<T: ?Sized + Send + Sync> MaybeSendSync for T {}
+
+#[cfg(not(feature = "send"))]
+type BoxFuture<'a, T> = futures::future::LocalBoxFuture<'a, T>;
+#[cfg(feature = "send")]
+type BoxFuture<'a, T> = futures::future::BoxFuture<'a, T>;
+
+#[cfg(not(feature = "send"))]
+fn boxed<'a, T>(future: impl 'a + Future<Output = T>) -> BoxFuture<'a, T> {
+    future.boxed_local()
+}
+#[cfg(feature = "send")]
+fn boxed<'a, T>(future: impl 'a + Send + Future<Output = T>) -> BoxFuture<'a, T> {
+    future.boxed()
+}
+
+struct CancelHandle {
+    abort_handle: AbortHandle,
+    cancellation_tx: oneshot::Sender<()>,
+}
+
+type ConnectionContext<In, Out> = HandlerContext<Arc<Connection<In, Out>>>;
+
+#[cfg(not(feature = "send"))]
+type RequestHandler<In, Out> = Box<
+    dyn 'static
+        + Fn(
+            In,
+            ConnectionContext<In, Out>,
+        ) -> BoxFuture<'static, Result<<In as AnyRequest>::Response>>,
+>;
+#[cfg(feature = "send")]
+type RequestHandler<In, Out> = Box<
+    dyn 'static
+        + Send
+        + Sync
+        + Fn(
+            In,
+            ConnectionContext<In, Out>,
+        ) -> BoxFuture<'static, Result<<In as AnyRequest>::Response>>,
+>;
+
+#[cfg(not(feature = "send"))]
+type NotificationHandler<In, Out> = Box<
+    dyn 'static
+        + Fn(
+            <In as AnyRequest>::Notification,
+            ConnectionContext<In, Out>,
+        ) -> BoxFuture<'static, Result<()>>,
+>;
+#[cfg(feature = "send")]
+type NotificationHandler<In, Out> = Box<
+    dyn 'static
+        + Send
+        + Sync
+        + Fn(
+            <In as AnyRequest>::Notification,
+            ConnectionContext<In, Out>,
+        ) -> BoxFuture<'static, Result<()>>,
+>;
+
+enum IncomingMessageParams<In: AnyRequest> {
+    Request { id: RequestId, params: In },
+    Notification(In::Notification),
+    Invalid { id: Option<RequestId>, error: Error },
+    Cancel(RequestId),
+}
+
+const JSONRPC_VERSION: &str = "2.0";
+
+const CANCEL_REQUEST_METHOD: &str = "$/cancelRequest";
+
+/// How long a handler may keep running after its request is cancelled,
+/// unless overridden with `set_cancellation_grace_period`.
+pub const DEFAULT_CANCELLATION_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CancelRequestParams {
+    id: RequestId,
+}
+
+/// The id of a JSON-RPC request, which may be either a number or a string.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(i64),
+    String(String),
+}
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestId::Number(id) => write!(f, "{id}"),
+            RequestId::String(id) => write!(f, "{id:?}"),
+        }
+    }
+}
 
 #[derive(Debug, Deserialize)]
 struct IncomingMessage<'a> {
-    id: i32,
+    jsonrpc: Option<&'a str>,
+    id: Option<RequestId>,
     method: Option<&'a str>,
     params: Option<&'a RawValue>,
     result: Option<&'a RawValue>,
     error: Option<Error>,
 }
 
+#[derive(Serialize)]
+struct JsonRpcMessage<'a, T> {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    message: &'a T,
+}
+
 #[derive(Serialize)]
 #[serde(untagged)]
-enum OutgoingMessage<Req, Resp> {
+enum OutgoingMessage<Req, Notif, Resp> {
     Request {
-        id: i32,
+        id: RequestId,
         method: Box<str>,
         params: Req,
     },
+    Notification {
+        method: Box<str>,
+        params: Notif,
+    },
+    CancelRequest {
+        method: &'static str,
+        params: CancelRequestParams,
+    },
     OkResponse {
-        id: i32,
+        id: RequestId,
         result: Resp,
     },
     ErrorResponse {
-        id: i32,
+        id: Option<RequestId>,
         error: Error,
     },
 }
@@ -155,34 +605,225 @@ enum OutgoingMessage<Req, Resp> {
 pub struct Error {
     pub code: i32,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+
+impl Error {
+    pub const PARSE_ERROR: i32 = -32700;
+    pub const INVALID_REQUEST: i32 = -32600;
+    pub const METHOD_NOT_FOUND: i32 = -32601;
+    pub const INVALID_PARAMS: i32 = -32602;
+    pub const INTERNAL_ERROR: i32 = -32603;
+    pub const REQUEST_TIMED_OUT: i32 = -32001;
+    pub const CONNECTION_CLOSED: i32 = -32002;
+    pub const UNSUPPORTED_PROTOCOL_VERSION: i32 = -32003;
+    pub const REQUEST_CANCELLED: i32 = -32800;
+
+    pub fn new(code: i32, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    pub fn with_data(mut self, data: impl Into<serde_json::Value>) -> Self {
+        self.data = Some(data.into());
+        self
+    }
+
+    pub fn parse_error(details: impl Display) -> Self {
+        Self::new(Self::PARSE_ERROR, format!("Parse error: {details}"))
+    }
+
+    pub fn invalid_request(details: impl Display) -> Self {
+        Self::new(Self::INVALID_REQUEST, format!("Invalid request: {details}"))
+    }
+
+    pub fn method_not_found(method: &str) -> Self {
+        Self::new(
+            Self::METHOD_NOT_FOUND,
+            format!("Method not found - '{method}'"),
+        )
+    }
+
+    pub fn invalid_params(details: impl Display) -> Self {
+        Self::new(Self::INVALID_PARAMS, format!("Invalid params: {details}"))
+    }
+
+    pub fn internal_error(details: impl Display) -> Self {
+        Self::new(Self::INTERNAL_ERROR, details.to_string())
+    }
+
+    pub fn request_timed_out(method: &str) -> Self {
+        Self::new(
+            Self::REQUEST_TIMED_OUT,
+            format!("Request timed out - '{method}'"),
+        )
+    }
+
+    pub fn connection_closed(reason: impl Display) -> Self {
+        Self::new(
+            Self::CONNECTION_CLOSED,
+            format!("Connection closed: {reason}"),
+        )
+    }
+
+    pub fn unsupported_protocol_version(version: ProtocolVersion) -> Self {
+        Self::new(
+            Self::UNSUPPORTED_PROTOCOL_VERSION,
+            format!(
+                "Unsupported protocol version {version}, expected {} to {}",
+                ProtocolVersion::MIN_SUPPORTED,
+                ProtocolVersion::LATEST
+            ),
+        )
+    }
+
+    pub fn request_cancelled() -> Self {
+        Self::new(Self::REQUEST_CANCELLED, "Request cancelled")
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<anyhow::Error> for Error {
+    /// Errors returned by handlers become internal errors, unless they
+    /// already are a protocol [`Error`] with a more specific code.
+    fn from(error: anyhow::Error) -> Self {
+        error.downcast().unwrap_or_else(Self::internal_error)
+    }
+}
+
+async fn write_message(
+    outgoing_bytes: &mut (impl Unpin + AsyncWrite),
+    outgoing_line: &mut Vec<u8>,
+    message: &impl Serialize,
+) -> Result<()> {
+    outgoing_line.clear();
+    serde_json::to_writer(
+        &mut *outgoing_line,
+        &JsonRpcMessage {
+            jsonrpc: JSONRPC_VERSION,
+            message,
+        },
+    )?;
+    log::trace!("send: {}", String::from_utf8_lossy(outgoing_line));
+    outgoing_line.push(b'\n');
+    outgoing_bytes
+        .write_all(outgoing_line)
+        .await
+        .context("failed to write message")?;
+    outgoing_bytes
+        .flush()
+        .await
+        .context("failed to flush message")?;
+    Ok(())
+}
+
+/// Tells the peer to stop working on a request when its response future is
+/// dropped before the response arrived.
+struct CancelOnDrop<In: AnyRequest, Out: AnyRequest> {
+    id: RequestId,
+    response_senders: ResponseSenders<Out::Response>,
+    outgoing_tx: UnboundedSender<OutgoingMessage<Out, Out::Notification, In::Response>>,
+}
+
+impl<In: AnyRequest, Out: AnyRequest> Drop for CancelOnDrop<In, Out> {
+    fn drop(&mut self) {
+        if self.response_senders.lock().remove(&self.id).is_some() {
+            self.outgoing_tx
+                .unbounded_send(OutgoingMessage::CancelRequest {
+                    method: CANCEL_REQUEST_METHOD,
+                    params: CancelRequestParams {
+                        id: self.id.clone(),
+                    },
+                })
+                .ok();
+        }
+    }
+}
+
+/// Checks an outgoing message before it is sent, so that payloads the peer
+/// would reject, such as oversized images, fail locally instead.
+trait Validate {
+    fn validate(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Validate for AnyAgentRequest {
+    fn validate(&self) -> Result<(), Error> {
+        match self {
+            Self::SendUserMessageParams(params) => params.message.validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for AnyClientRequest {}
+
+impl Validate for AnyAgentNotification {}
+
+impl Validate for AnyClientNotification {
+    fn validate(&self) -> Result<(), Error> {
+        match self {
+            Self::StreamUserMessageChunkParams(params) => params.chunk.validate(),
+            _ => Ok(()),
+        }
+    }
 }
 
 impl<In, Out> Connection<In, Out>
 where
     In: AnyRequest,
-    Out: AnyRequest,
+    Out: AnyRequest + Validate,
+    Out::Notification: Validate,
 {
     fn new(
-        request_handler: Box<dyn 'static + Fn(In) -> LocalBoxFuture<'static, Result<In::Response>>>,
+        request_handler: RequestHandler<In, Out>,
+        notification_handler: NotificationHandler<In, Out>,
         outgoing_bytes: impl Unpin + AsyncWrite,
         incoming_bytes: impl Unpin + AsyncRead,
     ) -> (
-        Self,
+        Arc<Self>,
         impl Future<Output = ()>,
         impl Future<Output = Result<()>>,
     ) {
         let (outgoing_tx, outgoing_rx) = mpsc::unbounded();
         let (incoming_tx, incoming_rx) = mpsc::unbounded();
-        let this = Self {
+        let (close_tx, close_rx) = oneshot::channel();
+        let this = Arc::new(Self {
             response_senders: ResponseSenders::default(),
+            concurrency_limits: ConcurrencyLimits::default(),
+            timeouts: Mutex::default(),
+            cancellation_grace_period: Mutex::new(Some(DEFAULT_CANCELLATION_GRACE_PERIOD)),
+            state: SharedState::default(),
+            close_tx: Mutex::new(Some(close_tx)),
             outgoing_tx: outgoing_tx.clone(),
-            next_id: AtomicI32::new(0),
-        };
-        let handler_task = Self::handle_incoming(outgoing_tx, incoming_rx, request_handler);
+            next_id: AtomicI64::new(0),
+        });
+        let handler_task = Self::handle_incoming(
+            this.clone(),
+            outgoing_tx,
+            incoming_rx,
+            this.concurrency_limits.clone(),
+            request_handler,
+            notification_handler,
+        );
         let io_task = Self::handle_io(
             outgoing_rx,
             incoming_tx,
+            close_rx,
             this.response_senders.clone(),
+            this.state.clone(),
             outgoing_bytes,
             incoming_bytes,
         );
@@ -193,125 +834,446 @@ where
         &self,
         method: &'static str,
         params: Out,
+    ) -> impl use<In, Out> + Future<Output = Result<Out::Response, crate::Error>> {
+        let timeouts = self.timeouts.lock();
+        let timeout = timeouts
+            .methods
+            .get(method)
+            .copied()
+            .unwrap_or(timeouts.default);
+        drop(timeouts);
+        self.request_with_timeout(method, params, timeout)
+    }
+
+    fn request_with_timeout(
+        &self,
+        method: &'static str,
+        params: Out,
+        timeout: Option<Duration>,
     ) -> impl use<In, Out> + Future<Output = Result<Out::Response, crate::Error>> {
         let (tx, rx) = oneshot::channel();
-        let id = self.next_id.fetch_add(1, SeqCst);
-        if self
-            .outgoing_tx
-            .unbounded_send(OutgoingMessage::Request {
-                id,
-                method: method.into(),
-                params,
-            })
-            .is_ok()
+        let validation = params.validate();
+        let is_connected = self.state.lock().state == ConnectionState::Connected;
+        let mut response_senders = self.response_senders.lock();
+        // The counter wraps around rather than overflowing, so skip any ids
+        // that are still waiting for a response from a previous lap.
+        let id = loop {
+            let id = RequestId::Number(self.next_id.fetch_add(1, SeqCst));
+            if !response_senders.contains_key(&id) {
+                break id;
+            }
+        };
+        if let Err(error) = validation {
+            tx.send(Err(error)).ok();
+        } else if is_connected
+            && self
+                .outgoing_tx
+                .unbounded_send(OutgoingMessage::Request {
+                    id: id.clone(),
+                    method: method.into(),
+                    params,
+                })
+                .is_ok()
         {
             // if the io thread has aborted, immediately drop tx.
-            self.response_senders.lock().insert(id, (method, tx));
+            response_senders.insert(id.clone(), (method, tx));
         }
+        drop(response_senders);
+
+        // Created eagerly so that dropping the future cancels the request,
+        // even if it was never polled.
+        let cancel_on_drop = CancelOnDrop::<In, Out> {
+            id,
+            response_senders: self.response_senders.clone(),
+            outgoing_tx: self.outgoing_tx.clone(),
+        };
+        let deadline = timeout.map(Delay::new);
+        let state = self.state.clone();
         async move {
-            rx.await.map_err(|_| Error {
-                code: -9,
-                message: "acp connection lost".to_string(),
-            })?
+            // Timing out drops the guard, which cancels the request.
+            let _cancel_on_drop = cancel_on_drop;
+            let response =
+                rx.map(|response| response.map_err(|_| state.lock().connection_closed_error())?);
+            match deadline {
+                Some(deadline) => match future::select(response, deadline).await {
+                    Either::Left((response, _)) => response,
+                    Either::Right(_) => Err(Error::request_timed_out(method)),
+                },
+                None => response.await,
+            }
+        }
+    }
+
+    fn set_default_timeout(&self, timeout: Option<Duration>) {
+        self.timeouts.lock().default = timeout;
+    }
+
+    fn set_timeout(&self, method: &'static str, timeout: Option<Duration>) {
+        self.timeouts.lock().methods.insert(method, timeout);
+    }
+
+    fn set_concurrency_limit(&self, method: &'static str, limit: Option<usize>) {
+        let mut limits = self.concurrency_limits.lock();
+        if let Some(limit) = limit {
+            limits.insert(method, limit.max(1));
+        } else {
+            limits.remove(method);
+        }
+    }
+
+    fn notify(&self, params: impl Into<Out::Notification>) -> Result<(), crate::Error> {
+        let state = self.state.lock();
+        if state.state != ConnectionState::Connected {
+            return Err(state.connection_closed_error());
+        }
+        drop(state);
+
+        let params = params.into();
+        params.validate()?;
+        self.outgoing_tx
+            .unbounded_send(OutgoingMessage::Notification {
+                method: params.method_name().into(),
+                params,
+            })
+            .map_err(|_| self.state.lock().connection_closed_error())
+    }
+
+    fn state(&self) -> ConnectionState {
+        self.state.lock().state.clone()
+    }
+
+    fn watch_state(&self) -> UnboundedReceiver<ConnectionState> {
+        let (tx, rx) = mpsc::unbounded();
+        let mut state = self.state.lock();
+        tx.unbounded_send(state.state.clone()).ok();
+        state.subscribers.push(tx);
+        rx
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock();
+        if state.state == ConnectionState::Connected {
+            state.set(ConnectionState::Closing);
+            if let Some(close_tx) = self.close_tx.lock().take() {
+                close_tx.send(()).ok();
+            }
         }
     }
 
     async fn handle_io(
-        mut outgoing_rx: UnboundedReceiver<OutgoingMessage<Out, In::Response>>,
-        incoming_tx: UnboundedSender<(i32, In)>,
+        mut outgoing_rx: UnboundedReceiver<OutgoingMessage<Out, Out::Notification, In::Response>>,
+        incoming_tx: UnboundedSender<IncomingMessageParams<In>>,
+        close_rx: oneshot::Receiver<()>,
         response_senders: ResponseSenders<Out::Response>,
+        state: SharedState,
         mut outgoing_bytes: impl Unpin + AsyncWrite,
         incoming_bytes: impl Unpin + AsyncRead,
     ) -> Result<()> {
         let mut output_reader = BufReader::new(incoming_bytes);
         let mut outgoing_line = Vec::new();
         let mut incoming_line = String::new();
-        loop {
-            select_biased! {
-                message = outgoing_rx.next() => {
-                    if let Some(message) = message {
-                        outgoing_line.clear();
-                        serde_json::to_writer(&mut outgoing_line, &message)?;
-                        log::trace!("send: {}", String::from_utf8_lossy(&outgoing_line));
-                        outgoing_line.push(b'\n');
-                        outgoing_bytes.write_all(&outgoing_line).await.ok();
-                    } else {
-                        break;
+        let mut close_rx = close_rx;
+        let result: Result<CloseReason> = async {
+            loop {
+                select_biased! {
+                    _ = close_rx => {
+                        while let Ok(Some(message)) = outgoing_rx.try_next() {
+                            write_message(&mut outgoing_bytes, &mut outgoing_line, &message).await?;
+                        }
+                        outgoing_bytes.close().await.context("failed to close connection")?;
+                        return Ok(CloseReason::Closed);
                     }
-                }
-                bytes_read = output_reader.read_line(&mut incoming_line).fuse() => {
-                    if bytes_read? == 0 {
-                        break
+                    message = outgoing_rx.next() => {
+                        if let Some(message) = message {
+                            write_message(&mut outgoing_bytes, &mut outgoing_line, &message).await?;
+                        } else {
+                            return Ok(CloseReason::Closed);
+                        }
                     }
-                    log::trace!("recv: {}", &incoming_line);
-                    match serde_json::from_str::<IncomingMessage>(&incoming_line) {
-                        Ok(message) => {
-                            if let Some(method) = message.method {
-                                match In::from_method_and_params(method, message.params.unwrap_or(RawValue::NULL)) {
-                                    Ok(params) => {
-                                        incoming_tx.unbounded_send((message.id, params)).ok();
-                                    }
-                                    Err(error) => {
-                                        log::error!("failed to parse incoming {method} message params: {error}. Raw: {incoming_line}");
+                    bytes_read = output_reader.read_line(&mut incoming_line).fuse() => {
+                        if bytes_read.context("failed to read message")? == 0 {
+                            return Ok(CloseReason::PeerDisconnected);
+                        }
+                        log::trace!("recv: {}", &incoming_line);
+                        match serde_json::from_str::<IncomingMessage>(&incoming_line) {
+                            Ok(message) if message.jsonrpc != Some(JSONRPC_VERSION) => {
+                                let error = Error::invalid_request(format!(
+                                    "expected \"jsonrpc\": \"{JSONRPC_VERSION}\", got {:?}",
+                                    message.jsonrpc
+                                ));
+                                log::error!("{error}. Raw: {incoming_line}");
+                                if let Some(id) = message.id {
+                                    if message.method.is_some() {
+                                        incoming_tx.unbounded_send(IncomingMessageParams::Invalid { id: Some(id), error }).ok();
+                                    } else if let Some((_, tx)) = response_senders.lock().remove(&id) {
+                                        tx.send(Err(error)).ok();
                                     }
                                 }
-                            } else if let Some(error) = message.error {
-                                if let Some((_, tx)) = response_senders.lock().remove(&message.id) {
-                                    tx.send(Err(error)).ok();
-                                }
-                            } else {
-                                let result = message.result.unwrap_or(RawValue::NULL);
-                                if let Some((method, tx)) = response_senders.lock().remove(&message.id) {
-                                    match Out::response_from_method_and_result(method, result) {
-                                        Ok(result) => {
-                                            tx.send(Ok(result)).ok();
+                            }
+                            Ok(message) => {
+                                let params = message.params.unwrap_or(RawValue::NULL);
+                                if let Some(method) = message.method {
+                                    if let Some(id) = message.id {
+                                        match In::from_method_and_params(method, params) {
+                                            Ok(params) => {
+                                                incoming_tx.unbounded_send(IncomingMessageParams::Request { id, params }).ok();
+                                            }
+                                            Err(error) => {
+                                                log::error!("failed to parse incoming {method} message params: {error}. Raw: {incoming_line}");
+                                                incoming_tx.unbounded_send(IncomingMessageParams::Invalid { id: Some(id), error }).ok();
+                                            }
+                                        }
+                                    } else if method == CANCEL_REQUEST_METHOD {
+                                        match serde_json::from_str::<CancelRequestParams>(params.get()) {
+                                            Ok(params) => {
+                                                incoming_tx.unbounded_send(IncomingMessageParams::Cancel(params.id)).ok();
+                                            }
+                                            Err(error) => {
+                                                log::error!("failed to parse incoming {method} notification params: {error}. Raw: {incoming_line}");
+                                            }
                                         }
-                                        Err(error) => {
-                                            log::error!("failed to parse {method} message result: {error}. Raw: {result}");
+                                    } else {
+                                        match In::Notification::from_method_and_params(method, params) {
+                                            Ok(params) => {
+                                                incoming_tx.unbounded_send(IncomingMessageParams::Notification(params)).ok();
+                                            }
+                                            Err(error) => {
+                                                log::error!("failed to parse incoming {method} notification params: {error}. Raw: {incoming_line}");
+                                            }
                                         }
                                     }
+                                } else if let Some(id) = message.id {
+                                    if let Some((method, tx)) = response_senders.lock().remove(&id) {
+                                        if let Some(error) = message.error {
+                                            tx.send(Err(error)).ok();
+                                        } else {
+                                            let result = message.result.unwrap_or(RawValue::NULL);
+                                            match Out::response_from_method_and_result(method, result) {
+                                                Ok(result) => {
+                                                    tx.send(Ok(result)).ok();
+                                                }
+                                                Err(error) => {
+                                                    log::error!("failed to parse {method} message result: {error}. Raw: {result}");
+                                                    tx.send(Err(Error::new(
+                                                        error.code,
+                                                        format!("Failed to parse {method} response: {}", error.message),
+                                                    ))).ok();
+                                                }
+                                            }
+                                        }
+                                    } else {
+                                        // This is expected for requests we have cancelled.
+                                        log::debug!("received response for unknown request {id}. Raw: {incoming_line}");
+                                    }
+                                } else if let Some(error) = message.error {
+                                    log::error!("received error response without an id: {error}");
                                 } else {
-                                    dbg!(&message.id, response_senders.lock().keys().collect::<Vec<_>>());
+                                    log::error!("received message with neither an id nor a method. Raw: {incoming_line}");
                                 }
                             }
+                            Err(error) => {
+                                log::error!("failed to parse incoming message: {error}. Raw: {incoming_line}");
+                                // Valid JSON that isn't a valid message is an invalid request.
+                                let error = match error.classify() {
+                                    serde_json::error::Category::Data => Error::invalid_request(error),
+                                    _ => Error::parse_error(error),
+                                };
+                                incoming_tx.unbounded_send(IncomingMessageParams::Invalid { id: None, error }).ok();
+                            }
                         }
-                        Err(error) => {
-                            log::error!("failed to parse incoming message: {error}. Raw: {incoming_line}");
-                        }
+                        incoming_line.clear();
                     }
-                    incoming_line.clear();
                 }
             }
         }
-        response_senders.lock().clear();
-        Ok(())
+        .await;
+
+        let reason = match &result {
+            Ok(reason) => reason.clone(),
+            Err(error) => {
+                log::error!("acp connection failed: {error:#}");
+                CloseReason::Error(format!("{error:#}"))
+            }
+        };
+        // Stop accepting outgoing messages and update the state first, so
+        // that no new requests are sent. A request that raced with the state
+        // check fails to queue, instead of waiting on a sender that is never
+        // drained.
+        outgoing_rx.close();
+        state.lock().set(ConnectionState::Closed(reason.clone()));
+        for (_, (_, tx)) in response_senders.lock().drain() {
+            tx.send(Err(Error::connection_closed(&reason))).ok();
+        }
+        result.map(|_| ())
     }
 
     async fn handle_incoming(
-        outgoing_tx: UnboundedSender<OutgoingMessage<Out, In::Response>>,
-        mut incoming_rx: UnboundedReceiver<(i32, In)>,
-        incoming_handler: Box<
-            dyn 'static + Fn(In) -> LocalBoxFuture<'static, Result<In::Response>>,
-        >,
+        connection: Arc<Self>,
+        outgoing_tx: UnboundedSender<OutgoingMessage<Out, Out::Notification, In::Response>>,
+        mut incoming_rx: UnboundedReceiver<IncomingMessageParams<In>>,
+        concurrency_limits: ConcurrencyLimits,
+        request_handler: RequestHandler<In, Out>,
+        notification_handler: NotificationHandler<In, Out>,
     ) {
-        while let Some((id, params)) = incoming_rx.next().await {
-            let result = incoming_handler(params).await;
-            match result {
-                Ok(result) => {
-                    outgoing_tx
-                        .unbounded_send(OutgoingMessage::OkResponse { id, result })
-                        .ok();
-                }
-                Err(error) => {
-                    outgoing_tx
-                        .unbounded_send(OutgoingMessage::ErrorResponse {
-                            id,
-                            error: Error {
-                                code: -32603,
-                                message: error.to_string(),
-                            },
+        // Requests are handled concurrently, so that e.g. a cancellation can
+        // reach the agent while it is still handling a previous message.
+        let mut running_requests = FuturesUnordered::new();
+        let mut running_counts = HashMap::<&'static str, usize>::new();
+        let mut queued_requests = HashMap::<&'static str, VecDeque<(RequestId, In)>>::new();
+        let mut cancel_handles = HashMap::<RequestId, CancelHandle>::new();
+        let mut cancelled_requests = FuturesUnordered::new();
+        let start_request = |id: RequestId, params: In| {
+            let method = params.method_name();
+            let (abort_handle, abort_registration) = AbortHandle::new_pair();
+            let (cancellation_tx, cancellation) = CancellationSignal::new();
+            let cx = HandlerContext {
+                request_id: Some(id.clone()),
+                peer: connection.clone(),
+                cancellation,
+            };
+            let request = Abortable::new(request_handler(params, cx), abort_registration)
+                .map(move |result| (method, id, result));
+            (
+                request,
+                CancelHandle {
+                    abort_handle,
+                    cancellation_tx,
+                },
+            )
+        };
+
+        // Messages are dispatched in the order they arrive. Notifications are
+        // handled one at a time, and nothing received after a notification is
+        // dispatched until its handler has finished, so that e.g. streamed
+        // chunks can't be reordered or overtaken by a later request.
+        let mut pending_messages = VecDeque::<IncomingMessageParams<In>>::new();
+        let mut notification: future::Fuse<BoxFuture<'static, ()>> = future::Fuse::terminated();
+        let mut incoming_closed = false;
+        loop {
+            while notification.is_terminated() {
+                let Some(message) = pending_messages.pop_front() else {
+                    break;
+                };
+                match message {
+                    IncomingMessageParams::Request { id, params } => {
+                        let method = params.method_name();
+                        let limit = concurrency_limits.lock().get(method).copied();
+                        let count = running_counts.entry(method).or_default();
+                        if limit.is_some_and(|limit| *count >= limit) {
+                            queued_requests
+                                .entry(method)
+                                .or_default()
+                                .push_back((id, params));
+                        } else {
+                            *count += 1;
+                            let (request, cancel_handle) = start_request(id.clone(), params);
+                            cancel_handles.insert(id, cancel_handle);
+                            running_requests.push(request);
+                        }
+                    }
+                    IncomingMessageParams::Notification(params) => {
+                        let method = params.method_name();
+                        let cx = HandlerContext {
+                            request_id: None,
+                            peer: connection.clone(),
+                            cancellation: CancellationSignal::never(),
+                        };
+                        let handling = notification_handler(params, cx);
+                        notification = boxed(async move {
+                            if let Err(error) = handling.await {
+                                log::error!("failed to handle {method} notification: {error}");
+                            }
                         })
-                        .ok();
+                        .fuse();
+                    }
+                    IncomingMessageParams::Invalid { id, error } => {
+                        outgoing_tx
+                            .unbounded_send(OutgoingMessage::ErrorResponse { id, error })
+                            .ok();
+                    }
+                    IncomingMessageParams::Cancel(id) => {
+                        if let Some(cancel_handle) = cancel_handles.remove(&id) {
+                            // Let the handler respond to the cancellation itself,
+                            // and only abort it if it's still running once the
+                            // grace period has passed. The response is sent once
+                            // the aborted request is polled.
+                            cancel_handle.cancellation_tx.send(()).ok();
+                            let grace_period = *connection.cancellation_grace_period.lock();
+                            if let Some(grace_period) = grace_period {
+                                let abort_handle = cancel_handle.abort_handle;
+                                cancelled_requests.push(
+                                    Delay::new(grace_period).map(move |_| abort_handle.abort()),
+                                );
+                            }
+                        } else {
+                            for queue in queued_requests.values_mut() {
+                                if let Some(ix) =
+                                    queue.iter().position(|(queued_id, _)| *queued_id == id)
+                                {
+                                    queue.remove(ix);
+                                    outgoing_tx
+                                        .unbounded_send(OutgoingMessage::ErrorResponse {
+                                            id: Some(id),
+                                            error: Error::request_cancelled(),
+                                        })
+                                        .ok();
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            if incoming_closed && notification.is_terminated() {
+                break;
+            }
+
+            select_biased! {
+                () = notification => {}
+                () = cancelled_requests.select_next_some() => {}
+                message = incoming_rx.next() => match message {
+                    Some(message) => pending_messages.push_back(message),
+                    None => incoming_closed = true,
+                },
+                (method, id, result) = running_requests.select_next_some() => {
+                    cancel_handles.remove(&id);
+                    match result {
+                        Ok(Ok(result)) => {
+                            outgoing_tx
+                                .unbounded_send(OutgoingMessage::OkResponse { id, result })
+                                .ok();
+                        }
+                        Ok(Err(error)) => {
+                            outgoing_tx
+                                .unbounded_send(OutgoingMessage::ErrorResponse {
+                                    id: Some(id),
+                                    error: error.into(),
+                                })
+                                .ok();
+                        }
+                        Err(Aborted) => {
+                            outgoing_tx
+                                .unbounded_send(OutgoingMessage::ErrorResponse {
+                                    id: Some(id),
+                                    error: Error::request_cancelled(),
+                                })
+                                .ok();
+                        }
+                    }
+                    let limit = concurrency_limits.lock().get(method).copied();
+                    let count = running_counts.entry(method).or_default();
+                    *count -= 1;
+                    if let Some(queue) = queued_requests.get_mut(method) {
+                        while limit.is_none_or(|limit| *count < limit) {
+                            let Some((id, params)) = queue.pop_front() else {
+                                break;
+                            };
+                            *count += 1;
+                            let (request, cancel_handle) = start_request(id.clone(), params);
+                            cancel_handles.insert(id, cancel_handle);
+                            running_requests.push(request);
+                        }
+                    }
                 }
             }
         }
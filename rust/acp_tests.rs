@@ -1,51 +1,245 @@
 use super::*;
 use anyhow::Result;
 use async_trait::async_trait;
+use chrono::Utc;
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
 use tokio::task::LocalSet;
 use tokio::time::{Duration, timeout};
 
-pub struct TestClient;
-pub struct TestAgent;
+#[derive(Default)]
+pub struct TestClient {
+    chunks: Arc<Mutex<Vec<String>>>,
+    user_chunks: Arc<Mutex<Vec<String>>>,
+    files: Arc<Mutex<HashMap<PathBuf, String>>>,
+    terminals: Arc<Mutex<HashMap<TerminalId, TestTerminal>>>,
+    plan: Arc<Mutex<Vec<PlanEntry>>>,
+    locations: Arc<Mutex<Vec<ToolCallLocation>>>,
+    handled: Arc<Mutex<Vec<&'static str>>>,
+}
+
+struct TestTerminal {
+    output: String,
+    exit_status: Option<TerminalExitStatus>,
+}
+
+#[derive(Default)]
+pub struct TestAgent {
+    cancel_tx: Mutex<HashMap<ThreadId, oneshot::Sender<()>>>,
+    finish_tx: Arc<Mutex<HashMap<ThreadId, oneshot::Sender<()>>>>,
+    threads: Mutex<Vec<ThreadMetadata>>,
+    messages_started: Arc<Mutex<usize>>,
+    cancellation: Arc<Mutex<Option<CancellationSignal>>>,
+    workspace: Arc<Mutex<WorkspaceRoots>>,
+}
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send"), async_trait(?Send))]
+#[cfg_attr(feature = "send", async_trait)]
 impl Agent for TestAgent {
-    async fn initialize(&self, _request: InitializeParams) -> Result<InitializeResponse> {
+    async fn initialize(
+        &self,
+        request: InitializeParams,
+        _cx: HandlerContext<ClientConnection>,
+    ) -> Result<InitializeResponse> {
+        let protocol_version = request.protocol_version.negotiate()?;
+        *self.workspace.lock() = WorkspaceRoots::from_initialize(&request);
         Ok(InitializeResponse {
+            protocol_version,
+            agent_capabilities: AgentCapabilities {
+                cancellation: true,
+                images: true,
+            },
+            agent_info: Some(AgentInfo {
+                name: "test-agent".into(),
+                version: "1.0.0".into(),
+                homepage: None,
+                description: None,
+            }),
             is_authenticated: true,
         })
     }
 
-    async fn authenticate(&self, _request: AuthenticateParams) -> Result<AuthenticateResponse> {
+    async fn authenticate(
+        &self,
+        _request: AuthenticateParams,
+        cx: HandlerContext<ClientConnection>,
+    ) -> Result<AuthenticateResponse> {
+        anyhow::ensure!(cx.request_id().is_some());
+        // Call back into the client while handling the request.
+        cx.peer().notify(StreamAssistantMessageChunkParams {
+            thread_id: thread_id(),
+            chunk: AssistantMessageChunk::Text {
+                chunk: "Authenticating".into(),
+            },
+        })?;
+        cx.peer()
+            .request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "Sign in".into(),
+                icon: Icon::Globe,
+                kind: ToolKind::Fetch,
+                content: None,
+                locations: Vec::new(),
+            })
+            .await?;
         Ok(AuthenticateResponse)
     }
 
+    async fn new_thread(
+        &self,
+        _request: NewThreadParams,
+        _cx: HandlerContext<ClientConnection>,
+    ) -> Result<NewThreadResponse> {
+        let mut threads = self.threads.lock();
+        let number = threads.len() + 1;
+        let thread_id = ThreadId(format!("thread-{number}"));
+        threads.push(ThreadMetadata {
+            thread_id: thread_id.clone(),
+            title: format!("Thread {number}"),
+            modified_at: Utc::now(),
+        });
+        Ok(NewThreadResponse { thread_id })
+    }
+
+    async fn list_threads(
+        &self,
+        request: ListThreadsParams,
+        _cx: HandlerContext<ClientConnection>,
+    ) -> Result<ListThreadsResponse> {
+        let threads = self.threads.lock();
+        let start = match request.cursor {
+            Some(cursor) => cursor.parse().map_err(|_| Error::invalid_params(cursor))?,
+            None => 0,
+        };
+        let end = request
+            .limit
+            .map_or(threads.len(), |limit| start + limit as usize)
+            .min(threads.len());
+        Ok(ListThreadsResponse {
+            threads: threads.get(start..end).unwrap_or_default().to_vec(),
+            next_cursor: (end < threads.len()).then(|| end.to_string()),
+        })
+    }
+
+    async fn open_thread(
+        &self,
+        request: OpenThreadParams,
+        cx: HandlerContext<ClientConnection>,
+    ) -> Result<OpenThreadResponse> {
+        if !self
+            .threads
+            .lock()
+            .iter()
+            .any(|thread| thread.thread_id == request.thread_id)
+        {
+            return Err(Error::invalid_params(format!("no thread {}", request.thread_id)).into());
+        }
+        cx.peer().notify(StreamUserMessageChunkParams {
+            thread_id: request.thread_id.clone(),
+            chunk: "What's up?".into(),
+        })?;
+        cx.peer().notify(StreamAssistantMessageChunkParams {
+            thread_id: request.thread_id,
+            chunk: AssistantMessageChunk::Text {
+                chunk: "Not much".into(),
+            },
+        })?;
+        Ok(OpenThreadResponse)
+    }
+
+    async fn close_thread(
+        &self,
+        request: CloseThreadParams,
+        _cx: HandlerContext<ClientConnection>,
+    ) -> Result<CloseThreadResponse> {
+        if let Some(cancel_tx) = self.cancel_tx.lock().remove(&request.thread_id) {
+            cancel_tx.send(()).ok();
+        }
+        Ok(CloseThreadResponse)
+    }
+
     async fn send_user_message(
         &self,
-        _request: SendUserMessageParams,
+        request: SendUserMessageParams,
+        cx: HandlerContext<ClientConnection>,
     ) -> Result<SendUserMessageResponse> {
-        Ok(SendUserMessageResponse)
+        *self.cancellation.lock() = Some(cx.cancellation().clone());
+        // Keep the turn running until the test finishes it or it is cancelled.
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let (finish_tx, finish_rx) = oneshot::channel();
+        self.cancel_tx
+            .lock()
+            .insert(request.thread_id.clone(), cancel_tx);
+        self.finish_tx.lock().insert(request.thread_id, finish_tx);
+        *self.messages_started.lock() += 1;
+        let stop_reason = futures::select_biased! {
+            _ = cancel_rx.fuse() => StopReason::Cancelled,
+            () = cx.cancellation().cancelled().fuse() => StopReason::Cancelled,
+            _ = finish_rx.fuse() => StopReason::EndTurn,
+        };
+        Ok(SendUserMessageResponse {
+            stop_reason,
+            detail: None,
+        })
     }
 
     async fn cancel_send_message(
         &self,
-        _request: CancelSendMessageParams,
+        request: CancelSendMessageParams,
+        _cx: HandlerContext<ClientConnection>,
     ) -> Result<CancelSendMessageResponse> {
+        if let Some(cancel_tx) = self.cancel_tx.lock().remove(&request.thread_id) {
+            cancel_tx.send(()).ok();
+        }
         Ok(CancelSendMessageResponse)
     }
+
+    async fn update_workspace_roots(
+        &self,
+        notification: UpdateWorkspaceRootsParams,
+        _cx: HandlerContext<ClientConnection>,
+    ) -> Result<()> {
+        self.workspace.lock().update(notification);
+        Ok(())
+    }
 }
 
-#[async_trait(?Send)]
+#[cfg_attr(not(feature = "send"), async_trait(?Send))]
+#[cfg_attr(feature = "send", async_trait)]
 impl Client for TestClient {
     async fn stream_assistant_message_chunk(
         &self,
-        _request: StreamAssistantMessageChunkParams,
-    ) -> Result<StreamAssistantMessageChunkResponse> {
-        Ok(StreamAssistantMessageChunkResponse {})
+        notification: StreamAssistantMessageChunkParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<()> {
+        // Yield before recording the chunk, so that a handler that was
+        // dispatched too early would get to run first.
+        tokio::task::yield_now().await;
+        match notification.chunk {
+            AssistantMessageChunk::Text { chunk } | AssistantMessageChunk::Thought { chunk } => {
+                self.chunks.lock().push(chunk);
+            }
+            AssistantMessageChunk::Unknown(_) => {}
+        }
+        self.handled.lock().push("chunk");
+        Ok(())
+    }
+
+    async fn stream_user_message_chunk(
+        &self,
+        notification: StreamUserMessageChunkParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<()> {
+        if let UserMessageChunk::Text { chunk } = notification.chunk {
+            self.user_chunks.lock().push(chunk);
+        }
+        Ok(())
     }
 
     async fn request_tool_call_confirmation(
         &self,
         _request: RequestToolCallConfirmationParams,
+        _cx: HandlerContext<AgentConnection>,
     ) -> Result<RequestToolCallConfirmationResponse> {
         Ok(RequestToolCallConfirmationResponse {
             id: ToolCallId(0),
@@ -53,45 +247,195 @@ impl Client for TestClient {
         })
     }
 
-    async fn push_tool_call(&self, _request: PushToolCallParams) -> Result<PushToolCallResponse> {
+    async fn push_tool_call(
+        &self,
+        request: PushToolCallParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<PushToolCallResponse> {
+        self.handled.lock().push("tool");
+        *self.locations.lock() = request.locations;
         Ok(PushToolCallResponse { id: ToolCallId(0) })
     }
 
     async fn update_tool_call(
         &self,
-        _request: UpdateToolCallParams,
+        request: UpdateToolCallParams,
+        _cx: HandlerContext<AgentConnection>,
     ) -> Result<UpdateToolCallResponse> {
+        if let Some(locations) = request.locations {
+            *self.locations.lock() = locations;
+        }
         Ok(UpdateToolCallResponse)
     }
+
+    async fn update_plan(
+        &self,
+        request: UpdatePlanParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<UpdatePlanResponse> {
+        *self.plan.lock() = request.entries;
+        Ok(UpdatePlanResponse)
+    }
+
+    async fn read_text_file(
+        &self,
+        request: ReadTextFileParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<ReadTextFileResponse> {
+        let files = self.files.lock();
+        let content = files.get(&request.path).ok_or_else(|| {
+            Error::invalid_params(format!("{} does not exist", request.path.display()))
+        })?;
+        // Lines are 1-based.
+        let start = request
+            .line
+            .map_or(0, |line| line.saturating_sub(1) as usize);
+        let limit = request.limit.map_or(usize::MAX, |limit| limit as usize);
+        let content = content
+            .split_inclusive('\n')
+            .skip(start)
+            .take(limit)
+            .collect();
+        Ok(ReadTextFileResponse { content })
+    }
+
+    async fn write_text_file(
+        &self,
+        request: WriteTextFileParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<WriteTextFileResponse> {
+        self.files.lock().insert(request.path, request.content);
+        Ok(WriteTextFileResponse)
+    }
+
+    async fn create_terminal(
+        &self,
+        request: CreateTerminalParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<CreateTerminalResponse> {
+        let mut terminals = self.terminals.lock();
+        let terminal_id = TerminalId(format!("terminal-{}", terminals.len() + 1));
+        let output = format!("$ {} {}\n", request.command, request.args.join(" "));
+        terminals.insert(
+            terminal_id.clone(),
+            TestTerminal {
+                output,
+                exit_status: None,
+            },
+        );
+        Ok(CreateTerminalResponse { terminal_id })
+    }
+
+    async fn terminal_output(
+        &self,
+        request: TerminalOutputParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<TerminalOutputResponse> {
+        let terminals = self.terminals.lock();
+        let terminal = terminals
+            .get(&request.terminal_id)
+            .ok_or_else(|| Error::invalid_params(request.terminal_id.to_string()))?;
+        Ok(TerminalOutputResponse {
+            output: terminal.output.clone(),
+            exit_status: terminal.exit_status.clone(),
+        })
+    }
+
+    async fn wait_for_terminal_exit(
+        &self,
+        request: WaitForTerminalExitParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<WaitForTerminalExitResponse> {
+        let mut terminals = self.terminals.lock();
+        let terminal = terminals
+            .get_mut(&request.terminal_id)
+            .ok_or_else(|| Error::invalid_params(request.terminal_id.to_string()))?;
+        let exit_status = terminal.exit_status.get_or_insert(TerminalExitStatus {
+            exit_code: Some(0),
+            signal: None,
+        });
+        Ok(WaitForTerminalExitResponse {
+            exit_status: exit_status.clone(),
+        })
+    }
+
+    async fn kill_terminal(
+        &self,
+        request: KillTerminalParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<KillTerminalResponse> {
+        let mut terminals = self.terminals.lock();
+        let terminal = terminals
+            .get_mut(&request.terminal_id)
+            .ok_or_else(|| Error::invalid_params(request.terminal_id.to_string()))?;
+        terminal.exit_status.get_or_insert(TerminalExitStatus {
+            exit_code: None,
+            signal: Some("SIGKILL".into()),
+        });
+        Ok(KillTerminalResponse)
+    }
+
+    async fn release_terminal(
+        &self,
+        request: ReleaseTerminalParams,
+        _cx: HandlerContext<AgentConnection>,
+    ) -> Result<ReleaseTerminalResponse> {
+        self.terminals
+            .lock()
+            .remove(&request.terminal_id)
+            .ok_or_else(|| Error::invalid_params(request.terminal_id.to_string()))?;
+        Ok(ReleaseTerminalResponse)
+    }
+}
+
+fn thread_id() -> ThreadId {
+    ThreadId("test-thread".into())
+}
+
+fn connect(client: TestClient, agent: TestAgent) -> (AgentConnection, ClientConnection) {
+    let (client_to_agent_tx, client_to_agent_rx) = async_pipe::pipe();
+    let (agent_to_client_tx, agent_to_client_rx) = async_pipe::pipe();
+
+    let (client_connection, client_handle_task, client_io_task) =
+        AgentConnection::connect_to_agent(client, client_to_agent_tx, agent_to_client_rx);
+    let (agent_connection, agent_handle_task, agent_io_task) =
+        ClientConnection::connect_to_client(agent, agent_to_client_tx, client_to_agent_rx);
+
+    tokio::task::spawn_local(client_handle_task);
+    tokio::task::spawn_local(agent_handle_task);
+    tokio::spawn(client_io_task);
+    tokio::spawn(agent_io_task);
+
+    (client_connection, agent_connection)
+}
+
+async fn wait_until(condition: impl Fn() -> bool) {
+    timeout(Duration::from_secs(2), async {
+        while !condition() {
+            tokio::task::yield_now().await;
+        }
+    })
+    .await
+    .unwrap();
 }
 
 #[tokio::test]
 async fn test_client_agent_communication() {
-    env_logger::init();
+    env_logger::try_init().ok();
 
     let local = LocalSet::new();
     local
         .run_until(async move {
-            let client = TestClient;
-            let agent = TestAgent;
-
-            let (client_to_agent_tx, client_to_agent_rx) = async_pipe::pipe();
-            let (agent_to_client_tx, agent_to_client_rx) = async_pipe::pipe();
-
-            let (client_connection, client_handle_task, client_io_task) =
-                AgentConnection::connect_to_agent(client, client_to_agent_tx, agent_to_client_rx);
-            let (agent_connection, agent_handle_task, agent_io_task) =
-                ClientConnection::connect_to_client(agent, agent_to_client_tx, client_to_agent_rx);
-
-            let _task = tokio::task::spawn_local(client_handle_task);
-            let _task = tokio::task::spawn_local(agent_handle_task);
-            let _task = tokio::spawn(client_io_task);
-            let _task = tokio::spawn(agent_io_task);
+            let (client_connection, agent_connection) =
+                connect(TestClient::default(), TestAgent::default());
 
             let response = agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
                 label: "test".into(),
                 icon: Icon::FileSearch,
+                kind: ToolKind::Search,
                 content: None,
+                locations: Vec::new(),
             });
             let response = timeout(Duration::from_secs(2), response)
                 .await
@@ -99,12 +443,1645 @@ async fn test_client_agent_communication() {
                 .unwrap();
             assert_eq!(response.id, ToolCallId(0));
 
-            let response = client_connection.request(InitializeParams);
+            let response = client_connection.request(InitializeParams::default());
             let response = timeout(Duration::from_secs(2), response)
                 .await
                 .unwrap()
                 .unwrap();
-            assert_eq!(response.is_authenticated, true);
+            assert!(response.is_authenticated);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_handler_context() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let chunks = client.chunks.clone();
+            let (client_connection, _agent_connection) = connect(client, TestAgent::default());
+
+            // The agent notifies the client and waits for a request to the
+            // client before responding.
+            let response = client_connection.request(AuthenticateParams);
+            timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(*chunks.lock(), ["Authenticating"]);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_notifications() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let chunks = client.chunks.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            for chunk in ["Hello", ", world"] {
+                agent_connection
+                    .notify(StreamAssistantMessageChunkParams {
+                        thread_id: thread_id(),
+                        chunk: AssistantMessageChunk::Text {
+                            chunk: chunk.into(),
+                        },
+                    })
+                    .unwrap();
+            }
+
+            // Notifications get no response, so round-trip a request to make
+            // sure the client has handled everything sent before it.
+            let response = agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            });
+            timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(*chunks.lock(), ["Hello", ", world"]);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_notification_ordering() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let handled = client.handled.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            agent_connection
+                .notify(StreamAssistantMessageChunkParams {
+                    thread_id: thread_id(),
+                    chunk: AssistantMessageChunk::Text {
+                        chunk: "Hello".into(),
+                    },
+                })
+                .unwrap();
+            let response = agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            });
+            timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap();
+
+            // The request must not start until the notification sent before
+            // it has been handled.
+            assert_eq!(*handled.lock(), ["chunk", "tool"]);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_cancel_during_send_user_message() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let finish_tx = agent.finish_tx.clone();
+            let (client_connection, _agent_connection) = connect(TestClient::default(), agent);
+
+            // A turn that runs to completion ends normally.
+            let send = client_connection.request(SendUserMessageParams {
+                thread_id: thread_id(),
+                message: "Hello".into(),
+            });
+            let send = tokio::task::spawn_local(send);
+            wait_until(|| *messages_started.lock() == 1).await;
+            let finish = finish_tx.lock().remove(&thread_id()).unwrap();
+            finish.send(()).unwrap();
+            let response = timeout(Duration::from_secs(2), send)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(response.stop_reason, StopReason::EndTurn);
+
+            let send = client_connection.request(SendUserMessageParams {
+                thread_id: thread_id(),
+                message: "Hello again".into(),
+            });
+            let send = tokio::task::spawn_local(send);
+            wait_until(|| *messages_started.lock() == 2).await;
+
+            // The turn only ends once the agent has seen the cancellation.
+            let cancel = client_connection.request(CancelSendMessageParams {
+                thread_id: thread_id(),
+            });
+            timeout(Duration::from_secs(2), cancel)
+                .await
+                .unwrap()
+                .unwrap();
+            let response = timeout(Duration::from_secs(2), send)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(response.stop_reason, StopReason::Cancelled);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_concurrency_limit() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let (client_connection, agent_connection) = connect(TestClient::default(), agent);
+            agent_connection.set_concurrency_limit::<SendUserMessageParams>(Some(1));
+
+            let first =
+                tokio::task::spawn_local(client_connection.request(SendUserMessageParams {
+                    thread_id: thread_id(),
+                    message: "first".into(),
+                }));
+            let second =
+                tokio::task::spawn_local(client_connection.request(SendUserMessageParams {
+                    thread_id: thread_id(),
+                    message: "second".into(),
+                }));
+
+            // Requests of other types are not held back by the limit.
+            timeout(
+                Duration::from_secs(2),
+                client_connection.request(InitializeParams::default()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            wait_until(|| *messages_started.lock() == 1).await;
+
+            timeout(
+                Duration::from_secs(2),
+                client_connection.request(CancelSendMessageParams {
+                    thread_id: thread_id(),
+                }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            timeout(Duration::from_secs(2), first)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+
+            // The second message starts once the first one has finished.
+            wait_until(|| *messages_started.lock() == 2).await;
+            timeout(
+                Duration::from_secs(2),
+                client_connection.request(CancelSendMessageParams {
+                    thread_id: thread_id(),
+                }),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            timeout(Duration::from_secs(2), second)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        })
+        .await
+}
+
+/// Connects an agent to a raw pipe, so tests can exchange hand-written lines
+/// with it.
+fn connect_raw_client(
+    agent: TestAgent,
+) -> (
+    ClientConnection,
+    async_pipe::PipeWriter,
+    BufReader<async_pipe::PipeReader>,
+) {
+    let (client_to_agent_tx, client_to_agent_rx) = async_pipe::pipe();
+    let (agent_to_client_tx, agent_to_client_rx) = async_pipe::pipe();
+
+    let (agent_connection, agent_handle_task, agent_io_task) =
+        ClientConnection::connect_to_client(agent, agent_to_client_tx, client_to_agent_rx);
+    tokio::task::spawn_local(agent_handle_task);
+    tokio::spawn(agent_io_task);
+
+    (
+        agent_connection,
+        client_to_agent_tx,
+        BufReader::new(agent_to_client_rx),
+    )
+}
+
+async fn send_line(writer: &mut async_pipe::PipeWriter, line: &str) {
+    writer.write_all(line.as_bytes()).await.unwrap();
+    writer.write_all(b"\n").await.unwrap();
+}
+
+async fn recv_json(reader: &mut BufReader<async_pipe::PipeReader>) -> Value {
+    let mut line = String::new();
+    timeout(Duration::from_secs(2), reader.read_line(&mut line))
+        .await
+        .unwrap()
+        .unwrap();
+    serde_json::from_str(&line).unwrap()
+}
+
+#[tokio::test]
+async fn test_error_responses() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (_agent_connection, mut writer, mut reader) =
+                connect_raw_client(TestAgent::default());
+
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":1,"method":"unknownMethod","params":null}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], 1);
+            assert_eq!(response["error"]["code"], Error::METHOD_NOT_FOUND);
+
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":2,"method":"sendUserMessage","params":{}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], 2);
+            assert_eq!(response["error"]["code"], Error::INVALID_PARAMS);
+
+            send_line(&mut writer, "{not json").await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], Value::Null);
+            assert_eq!(response["error"]["code"], Error::PARSE_ERROR);
+
+            // Valid JSON that isn't a valid message is an invalid request.
+            for line in [
+                r#"{"jsonrpc":"2.0","id":1.5,"method":"initialize","params":null}"#,
+                r#"{"jsonrpc":"2.0","id":{},"method":"initialize","params":null}"#,
+                r#"[{"jsonrpc":"2.0","id":1,"method":"initialize","params":null}]"#,
+            ] {
+                send_line(&mut writer, line).await;
+                let response = recv_json(&mut reader).await;
+                assert_eq!(response["id"], Value::Null);
+                assert_eq!(response["error"]["code"], Error::INVALID_REQUEST, "{line}");
+            }
+
+            // Notifications never get a response, even when they are invalid.
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","method":"unknownNotification","params":null}"#,
+            )
+            .await;
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":3,"method":"initialize","params":{"protocolVersion":1}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], 3);
+            assert_eq!(response["result"]["isAuthenticated"], true);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_unparseable_response() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (agent_connection, mut writer, mut reader) =
+                connect_raw_client(TestAgent::default());
+
+            let response = tokio::task::spawn_local(agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            }));
+            let request = recv_json(&mut reader).await;
+            assert_eq!(request["method"], "pushToolCall");
+
+            send_line(
+                &mut writer,
+                &json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "result": { "id": "not a number" },
+                })
+                .to_string(),
+            )
+            .await;
+            let error = timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(error.code, Error::PARSE_ERROR);
+            assert!(error.message.contains("pushToolCall"), "{error}");
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_jsonrpc_wire_format() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (agent_connection, mut writer, mut reader) =
+                connect_raw_client(TestAgent::default());
+
+            // String ids are echoed back as-is.
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":"init","method":"initialize","params":{"protocolVersion":1}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(
+                response,
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": "init",
+                    "result": {
+                        "protocolVersion": 1,
+                        "agentCapabilities": { "cancellation": true, "images": true },
+                        "agentInfo": { "name": "test-agent", "version": "1.0.0" },
+                        "isAuthenticated": true,
+                    },
+                })
+            );
+
+            // Messages must declare the JSON-RPC version.
+            send_line(
+                &mut writer,
+                r#"{"id":1,"method":"initialize","params":{"protocolVersion":1}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["jsonrpc"], "2.0");
+            assert_eq!(response["id"], 1);
+            assert_eq!(response["error"]["code"], Error::INVALID_REQUEST);
+
+            // Error data is passed through to the requester.
+            let response = tokio::task::spawn_local(agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            }));
+            let request = recv_json(&mut reader).await;
+            assert_eq!(request["jsonrpc"], "2.0");
+            send_line(
+                &mut writer,
+                &json!({
+                    "jsonrpc": "2.0",
+                    "id": request["id"],
+                    "error": { "code": 1, "message": "nope", "data": { "reason": "test" } },
+                })
+                .to_string(),
+            )
+            .await;
+            let error = timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(error.code, 1);
+            assert_eq!(error.data, Some(json!({ "reason": "test" })));
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_cancel_request() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let cancellation = agent.cancellation.clone();
+            let (agent_connection, mut writer, mut reader) = connect_raw_client(agent);
+
+            // Dropping a pending request tells the peer to cancel it.
+            let response = agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            });
+            let request = recv_json(&mut reader).await;
+            drop(response);
+            let cancel = recv_json(&mut reader).await;
+            assert_eq!(
+                cancel,
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "$/cancelRequest",
+                    "params": { "id": request["id"] },
+                })
+            );
+
+            // Cancelling an incoming request lets its handler respond.
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":1,"method":"sendUserMessage","params":{"threadId":"test-thread","message":{"chunks":[]}}}"#,
+            )
+            .await;
+            wait_until(|| *messages_started.lock() == 1).await;
+            let cancellation = cancellation.lock().clone().unwrap();
+            assert!(!cancellation.is_cancelled());
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":1}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], 1);
+            assert_eq!(response["result"]["stopReason"], "cancelled");
+            assert!(cancellation.is_cancelled());
+            cancellation.cancelled().await;
+
+            // A handler that ignores the cancellation is aborted once the
+            // grace period has passed. This one waits for a tool call that is
+            // never answered.
+            agent_connection.set_cancellation_grace_period(Some(Duration::from_millis(10)));
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":2,"method":"authenticate","params":null}"#,
+            )
+            .await;
+            assert_eq!(
+                recv_json(&mut reader).await["method"],
+                "streamAssistantMessageChunk"
+            );
+            let tool_call = recv_json(&mut reader).await;
+            assert_eq!(tool_call["method"], "pushToolCall");
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","method":"$/cancelRequest","params":{"id":2}}"#,
+            )
+            .await;
+            // Dropping the handler also cancels the request it was waiting on.
+            let cancel = recv_json(&mut reader).await;
+            assert_eq!(cancel["method"], "$/cancelRequest");
+            assert_eq!(cancel["params"]["id"], tool_call["id"]);
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["id"], 2);
+            assert_eq!(response["error"]["code"], Error::REQUEST_CANCELLED);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_request_timeout() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (agent_connection, _writer, mut reader) = connect_raw_client(TestAgent::default());
+
+            let error = agent_connection
+                .request_with_timeout(
+                    PushToolCallParams {
+                        thread_id: thread_id(),
+                        label: "test".into(),
+                        icon: Icon::FileSearch,
+                        kind: ToolKind::Search,
+                        content: None,
+                        locations: Vec::new(),
+                    },
+                    Duration::from_millis(10),
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::REQUEST_TIMED_OUT);
+
+            // The timed out request is cancelled on the other side.
+            let request = recv_json(&mut reader).await;
+            let cancel = recv_json(&mut reader).await;
+            assert_eq!(cancel["method"], "$/cancelRequest");
+            assert_eq!(cancel["params"]["id"], request["id"]);
+
+            // Per-method overrides take precedence over the default.
+            agent_connection.set_default_timeout(Some(Duration::from_secs(60)));
+            agent_connection.set_timeout::<PushToolCallParams>(Some(Duration::from_millis(10)));
+            let error = agent_connection
+                .request(PushToolCallParams {
+                    thread_id: thread_id(),
+                    label: "test".into(),
+                    icon: Icon::FileSearch,
+                    kind: ToolKind::Search,
+                    content: None,
+                    locations: Vec::new(),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::REQUEST_TIMED_OUT);
+        })
+        .await
+}
+
+#[cfg(feature = "send")]
+#[tokio::test(flavor = "multi_thread")]
+async fn test_send_connection() {
+    env_logger::try_init().ok();
+
+    let (client_to_agent_tx, client_to_agent_rx) = async_pipe::pipe();
+    let (agent_to_client_tx, agent_to_client_rx) = async_pipe::pipe();
+
+    let (client_connection, client_handle_task, client_io_task) = AgentConnection::connect_to_agent(
+        TestClient::default(),
+        client_to_agent_tx,
+        agent_to_client_rx,
+    );
+    let (_agent_connection, agent_handle_task, agent_io_task) = ClientConnection::connect_to_client(
+        TestAgent::default(),
+        agent_to_client_tx,
+        client_to_agent_rx,
+    );
+
+    tokio::spawn(client_handle_task);
+    tokio::spawn(agent_handle_task);
+    tokio::spawn(client_io_task);
+    tokio::spawn(agent_io_task);
+
+    // A single connection can be shared between worker threads.
+    let client_connection = Arc::new(client_connection);
+    let response = tokio::spawn({
+        let client_connection = client_connection.clone();
+        async move { client_connection.request(InitializeParams::default()).await }
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    assert!(response.is_authenticated);
+}
+
+#[cfg(all(feature = "process", unix))]
+#[tokio::test]
+async fn test_agent_process() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            // A minimal agent that answers a single initialize request, and
+            // only exits successfully if it receives a notification after it.
+            let script = r#"
+                echo "starting $AGENT_NAME in $(pwd)" >&2
+                # Keeps stderr open after the agent exits.
+                sleep 5 < /dev/null > /dev/null &
+                read request
+                echo '{"jsonrpc":"2.0","id":0,"result":{"protocolVersion":1,"isAuthenticated":true}}'
+                read notification
+                case "$notification" in
+                    *updateWorkspaceRoots*) exit 0 ;;
+                    *) exit 1 ;;
+                esac
+            "#;
+            let (process, handler_task, io_task, stderr_task) = AgentProcess::spawn(
+                AgentCommand {
+                    path: "sh".into(),
+                    args: vec!["-c".into(), script.into()],
+                    env: [("AGENT_NAME".into(), "test-agent".into())].into(),
+                    cwd: Some(std::env::temp_dir()),
+                },
+                TestClient::default(),
+            )
+            .unwrap();
+            tokio::task::spawn_local(handler_task);
+            let io_task = tokio::task::spawn_local(io_task);
+            tokio::task::spawn_local(stderr_task);
+
+            let response = timeout(
+                Duration::from_secs(5),
+                process.connection().request(InitializeParams::default()),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert!(response.is_authenticated);
+
+            // Messages that are still queued are written before stdin is
+            // closed.
+            process
+                .connection()
+                .notify(UpdateWorkspaceRootsParams {
+                    workspace_roots: vec![std::env::temp_dir()],
+                    cwd: None,
+                })
+                .unwrap();
+            let status = process.shutdown(Duration::from_secs(5)).await.unwrap();
+            assert!(status.success());
+            // The connection ends with the agent, even though its stderr
+            // is still open.
+            timeout(Duration::from_secs(2), io_task)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+
+            // Agents that don't exit when stdin is closed are killed.
+            let (process, _handler_task, _io_task, _stderr_task) = AgentProcess::spawn(
+                AgentCommand {
+                    path: "sleep".into(),
+                    args: vec!["30".into()],
+                    ..Default::default()
+                },
+                TestClient::default(),
+            )
+            .unwrap();
+            let status = timeout(
+                Duration::from_secs(5),
+                process.shutdown(Duration::from_millis(10)),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert!(!status.success());
+        })
+        .await
+}
+
+struct FailingWriter;
+
+impl AsyncWrite for FailingWriter {
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        _buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn test_write_failure() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (_agent_to_client_tx, agent_to_client_rx) = async_pipe::pipe();
+            let (client_connection, handler_task, io_task) = AgentConnection::connect_to_agent(
+                TestClient::default(),
+                FailingWriter,
+                agent_to_client_rx,
+            );
+            tokio::task::spawn_local(handler_task);
+            let io_task = tokio::task::spawn_local(io_task);
+
+            let error = timeout(
+                Duration::from_secs(2),
+                client_connection.request(InitializeParams::default()),
+            )
+            .await
+            .unwrap()
+            .unwrap_err();
+            assert_eq!(error.code, Error::CONNECTION_CLOSED);
+
+            assert!(io_task.await.unwrap().is_err());
+            assert!(matches!(
+                client_connection.state(),
+                ConnectionState::Closed(CloseReason::Error(_))
+            ));
+
+            // Requests made after the connection closed fail immediately.
+            let error = client_connection
+                .request(InitializeParams::default())
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::CONNECTION_CLOSED);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_connection_state() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (agent_connection, writer, mut reader) = connect_raw_client(TestAgent::default());
+            let mut states = agent_connection.watch_state();
+            assert_eq!(states.next().await, Some(ConnectionState::Connected));
+
+            // Pending requests fail when the peer disconnects.
+            let response = agent_connection.request(PushToolCallParams {
+                thread_id: thread_id(),
+                label: "test".into(),
+                icon: Icon::FileSearch,
+                kind: ToolKind::Search,
+                content: None,
+                locations: Vec::new(),
+            });
+            recv_json(&mut reader).await;
+            drop(writer);
+            let error = timeout(Duration::from_secs(2), response)
+                .await
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(error.code, Error::CONNECTION_CLOSED);
+            assert_eq!(
+                states.next().await,
+                Some(ConnectionState::Closed(CloseReason::PeerDisconnected))
+            );
+
+            // Closing the connection writes queued messages first.
+            let (agent_connection, _writer, mut reader) = connect_raw_client(TestAgent::default());
+            let mut states = agent_connection.watch_state();
+            agent_connection
+                .notify(StreamAssistantMessageChunkParams {
+                    thread_id: thread_id(),
+                    chunk: AssistantMessageChunk::Text {
+                        chunk: "bye".into(),
+                    },
+                })
+                .unwrap();
+            agent_connection.close();
+            assert_eq!(agent_connection.state(), ConnectionState::Closing);
+            assert!(
+                agent_connection
+                    .notify(StreamAssistantMessageChunkParams {
+                        thread_id: thread_id(),
+                        chunk: AssistantMessageChunk::Text {
+                            chunk: "too late".into(),
+                        },
+                    })
+                    .is_err()
+            );
+
+            let message = recv_json(&mut reader).await;
+            assert_eq!(message["params"]["chunk"]["chunk"], "bye");
+            let mut line = String::new();
+            assert_eq!(reader.read_line(&mut line).await.unwrap(), 0);
+
+            assert_eq!(states.next().await, Some(ConnectionState::Connected));
+            assert_eq!(states.next().await, Some(ConnectionState::Closing));
+            assert_eq!(
+                states.next().await,
+                Some(ConnectionState::Closed(CloseReason::Closed))
+            );
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_protocol_version() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (client_connection, _agent_connection) =
+                connect(TestClient::default(), TestAgent::default());
+
+            let response = client_connection
+                .request(InitializeParams {
+                    protocol_version: ProtocolVersion::LATEST,
+                    client_capabilities: ClientCapabilities {
+                        diffs: true,
+                        thoughts: false,
+                        fs: true,
+                    },
+                    client_info: Some(ClientInfo {
+                        name: "test-client".into(),
+                        version: "1.0.0".into(),
+                    }),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            response.protocol_version.ensure_supported().unwrap();
+            assert!(response.agent_capabilities.cancellation);
+            assert_eq!(response.agent_info.unwrap().name, "test-agent");
+
+            // A client on a newer version than the agent is answered with
+            // the agent's version, which it can fall back to.
+            let response = client_connection
+                .request(InitializeParams {
+                    protocol_version: ProtocolVersion(ProtocolVersion::LATEST.0 + 1),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.protocol_version, ProtocolVersion::LATEST);
+
+            // Versions older than the oldest supported one are rejected.
+            let error = client_connection
+                .request(InitializeParams {
+                    protocol_version: ProtocolVersion(ProtocolVersion::MIN_SUPPORTED.0 - 1),
+                    ..Default::default()
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::UNSUPPORTED_PROTOCOL_VERSION);
+            assert!(
+                ProtocolVersion(ProtocolVersion::LATEST.0 + 1)
+                    .ensure_supported()
+                    .is_err()
+            );
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_unversioned_peers() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let (_agent_connection, mut writer, mut reader) =
+                connect_raw_client(TestAgent::default());
+
+            // Clients that predate version negotiation get a version error.
+            for (id, params) in [(1, "null"), (2, "{}")] {
+                send_line(
+                    &mut writer,
+                    &format!(
+                        r#"{{"jsonrpc":"2.0","id":{id},"method":"initialize","params":{params}}}"#
+                    ),
+                )
+                .await;
+                let response = recv_json(&mut reader).await;
+                assert_eq!(response["id"], id);
+                assert_eq!(
+                    response["error"]["code"],
+                    Error::UNSUPPORTED_PROTOCOL_VERSION
+                );
+            }
+
+            // So do agents that predate it.
+            let response: InitializeResponse =
+                serde_json::from_value(json!({ "isAuthenticated": true })).unwrap();
+            assert_eq!(response.protocol_version, ProtocolVersion::V0);
+            let error = response.protocol_version.ensure_supported().unwrap_err();
+            assert_eq!(error.code, Error::UNSUPPORTED_PROTOCOL_VERSION);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_workspace_roots() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let workspace = agent.workspace.clone();
+            let (client_connection, _agent_connection) = connect(TestClient::default(), agent);
+
+            client_connection
+                .request(InitializeParams {
+                    workspace_roots: vec!["/work/a".into(), "/work/b".into()],
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+            {
+                let workspace = workspace.lock();
+                assert_eq!(workspace.cwd(), Some(Path::new("/work/a")));
+                assert_eq!(
+                    workspace.resolve("src/main.rs").unwrap(),
+                    Path::new("/work/a/src/main.rs")
+                );
+                assert_eq!(
+                    workspace.resolve("/work/b/./lib/../README.md").unwrap(),
+                    Path::new("/work/b/README.md")
+                );
+                let error = workspace.resolve("../../etc/passwd").unwrap_err();
+                assert_eq!(error.code, Error::INVALID_PARAMS);
+                assert!(workspace.resolve("/work/ab").is_err());
+            }
+
+            client_connection
+                .notify(UpdateWorkspaceRootsParams {
+                    workspace_roots: vec!["/work/c".into()],
+                    cwd: Some("/work/c/crates".into()),
+                })
+                .unwrap();
+            wait_until(|| workspace.lock().roots() == [PathBuf::from("/work/c")]).await;
+            let workspace = workspace.lock();
+            assert_eq!(
+                workspace.resolve("foo").unwrap(),
+                Path::new("/work/c/crates/foo")
+            );
+            assert!(workspace.resolve("/work/a/src/main.rs").is_err());
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_threads() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let (client_connection, _agent_connection) = connect(TestClient::default(), agent);
+
+            let first = client_connection
+                .request(NewThreadParams)
+                .await
+                .unwrap()
+                .thread_id;
+            let second = client_connection
+                .request(NewThreadParams)
+                .await
+                .unwrap()
+                .thread_id;
+            assert_ne!(first, second);
+
+            let first_send =
+                tokio::task::spawn_local(client_connection.request(SendUserMessageParams {
+                    thread_id: first.clone(),
+                    message: "first".into(),
+                }));
+            let mut second_send =
+                tokio::task::spawn_local(client_connection.request(SendUserMessageParams {
+                    thread_id: second.clone(),
+                    message: "second".into(),
+                }));
+            wait_until(|| *messages_started.lock() == 2).await;
+
+            // Cancelling one thread leaves the other one running.
+            client_connection
+                .request(CancelSendMessageParams { thread_id: first })
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(2), first_send)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert!(
+                timeout(Duration::from_millis(50), &mut second_send)
+                    .await
+                    .is_err()
+            );
+
+            client_connection
+                .request(CloseThreadParams { thread_id: second })
+                .await
+                .unwrap();
+            timeout(Duration::from_secs(2), second_send)
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_thread_history() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let chunks = client.chunks.clone();
+            let user_chunks = client.user_chunks.clone();
+            let (client_connection, _agent_connection) = connect(client, TestAgent::default());
+
+            for _ in 0..3 {
+                client_connection.request(NewThreadParams).await.unwrap();
+            }
+
+            let page = client_connection
+                .request(ListThreadsParams {
+                    cursor: None,
+                    limit: Some(2),
+                })
+                .await
+                .unwrap();
+            let titles = page.threads.iter().map(|thread| thread.title.as_str());
+            assert_eq!(titles.collect::<Vec<_>>(), ["Thread 1", "Thread 2"]);
+            let page = client_connection
+                .request(ListThreadsParams {
+                    cursor: page.next_cursor,
+                    limit: Some(2),
+                })
+                .await
+                .unwrap();
+            assert_eq!(page.threads.len(), 1);
+            assert_eq!(page.threads[0].title, "Thread 3");
+            assert_eq!(page.next_cursor, None);
+
+            client_connection
+                .request(OpenThreadParams {
+                    thread_id: page.threads[0].thread_id.clone(),
+                })
+                .await
+                .unwrap();
+            // The agent replays the thread before responding, but responses
+            // don't wait for notification handlers, so the replay may still be
+            // in flight here.
+            wait_until(|| !chunks.lock().is_empty() && !user_chunks.lock().is_empty()).await;
+            assert_eq!(*user_chunks.lock(), ["What's up?"]);
+            assert_eq!(*chunks.lock(), ["Not much"]);
+
+            let error = client_connection
+                .request(OpenThreadParams {
+                    thread_id: ThreadId("missing".into()),
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_text_files() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let files = client.files.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            agent_connection
+                .request(WriteTextFileParams {
+                    thread_id: thread_id(),
+                    path: "/work/notes.txt".into(),
+                    content: "one\ntwo\nthree\nfour\n".into(),
+                })
+                .await
+                .unwrap();
+            assert!(files.lock().contains_key(Path::new("/work/notes.txt")));
+
+            let response = agent_connection
+                .request(ReadTextFileParams {
+                    thread_id: thread_id(),
+                    path: "/work/notes.txt".into(),
+                    line: None,
+                    limit: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.content, "one\ntwo\nthree\nfour\n");
+
+            let response = agent_connection
+                .request(ReadTextFileParams {
+                    thread_id: thread_id(),
+                    path: "/work/notes.txt".into(),
+                    line: Some(2),
+                    limit: Some(2),
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.content, "two\nthree\n");
+
+            let error = agent_connection
+                .request(ReadTextFileParams {
+                    thread_id: thread_id(),
+                    path: "/work/missing.txt".into(),
+                    line: None,
+                    limit: None,
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_terminals() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let terminals = client.terminals.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            let terminal_id = agent_connection
+                .request(CreateTerminalParams {
+                    thread_id: thread_id(),
+                    command: "cargo".into(),
+                    args: vec!["test".into()],
+                    env: HashMap::default(),
+                    cwd: Some("/work".into()),
+                })
+                .await
+                .unwrap()
+                .terminal_id;
+            agent_connection
+                .request(PushToolCallParams {
+                    thread_id: thread_id(),
+                    label: "cargo test".into(),
+                    icon: Icon::Terminal,
+                    kind: ToolKind::Execute,
+                    content: Some(ToolCallContent::Terminal {
+                        terminal_id: terminal_id.clone(),
+                    }),
+                    locations: Vec::new(),
+                })
+                .await
+                .unwrap();
+
+            let response = agent_connection
+                .request(TerminalOutputParams {
+                    thread_id: thread_id(),
+                    terminal_id: terminal_id.clone(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.output, "$ cargo test\n");
+            assert_eq!(response.exit_status, None);
+
+            let response = agent_connection
+                .request(WaitForTerminalExitParams {
+                    thread_id: thread_id(),
+                    terminal_id: terminal_id.clone(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.exit_status.exit_code, Some(0));
+
+            agent_connection
+                .request(ReleaseTerminalParams {
+                    thread_id: thread_id(),
+                    terminal_id: terminal_id.clone(),
+                })
+                .await
+                .unwrap();
+            assert!(terminals.lock().is_empty());
+
+            let error = agent_connection
+                .request(KillTerminalParams {
+                    thread_id: thread_id(),
+                    terminal_id,
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_plan() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let plan = client.plan.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            let entry = |content: &str, status| PlanEntry {
+                content: content.into(),
+                priority: PlanEntryPriority::Medium,
+                status,
+            };
+            agent_connection
+                .request(UpdatePlanParams {
+                    thread_id: thread_id(),
+                    entries: vec![
+                        entry("Read the code", PlanEntryStatus::InProgress),
+                        entry("Fix the bug", PlanEntryStatus::Pending),
+                    ],
+                })
+                .await
+                .unwrap();
+            assert_eq!(plan.lock().len(), 2);
+
+            // Each update replaces the whole plan.
+            let entries = vec![
+                entry("Read the code", PlanEntryStatus::Completed),
+                entry("Fix the bug", PlanEntryStatus::InProgress),
+                entry("Run the tests", PlanEntryStatus::Pending),
+            ];
+            agent_connection
+                .request(UpdatePlanParams {
+                    thread_id: thread_id(),
+                    entries: entries.clone(),
+                })
+                .await
+                .unwrap();
+            assert_eq!(*plan.lock(), entries);
+        })
+        .await
+}
+
+#[tokio::test]
+async fn test_image_validation() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let client = TestClient::default();
+            let user_chunks = client.user_chunks.clone();
+            let (client_connection, agent_connection) = connect(client, agent);
+
+            let response = client_connection
+                .request(InitializeParams::default())
+                .await
+                .unwrap();
+            assert!(response.agent_capabilities.images);
+
+            let image = |data: Option<String>, path: Option<PathBuf>| SendUserMessageParams {
+                thread_id: thread_id(),
+                message: UserMessageChunk::Image {
+                    mime_type: "image/png".into(),
+                    data,
+                    path,
+                }
+                .into(),
+            };
+
+            let oversized = "A".repeat(UserMessageChunk::MAX_IMAGE_SIZE / 3 * 4 + 4);
+            let error = client_connection
+                .request(image(Some(oversized), None))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+            let error = client_connection
+                .request_with_timeout(image(None, None), Duration::from_secs(2))
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+
+            // Chunks streamed to the client, e.g. when replaying a thread, are
+            // checked too.
+            let error = agent_connection
+                .notify(StreamUserMessageChunkParams {
+                    thread_id: thread_id(),
+                    chunk: UserMessageChunk::Image {
+                        mime_type: "image/png".into(),
+                        data: None,
+                        path: None,
+                    },
+                })
+                .unwrap_err();
+            assert_eq!(error.code, Error::INVALID_PARAMS);
+            agent_connection
+                .notify(StreamUserMessageChunkParams {
+                    thread_id: thread_id(),
+                    chunk: "Hello".into(),
+                })
+                .unwrap();
+            wait_until(|| *user_chunks.lock() == ["Hello"]).await;
+
+            // Only valid messages reach the agent.
+            let send = tokio::task::spawn_local(
+                client_connection.request(image(Some("iVBORw0KGgo=".into()), None)),
+            );
+            wait_until(|| *messages_started.lock() == 1).await;
+            client_connection
+                .request(CancelSendMessageParams {
+                    thread_id: thread_id(),
+                })
+                .await
+                .unwrap();
+            send.await.unwrap().unwrap();
+        })
+        .await
+}
+
+#[test]
+fn test_path_chunks() {
+    let chunk = UserMessageChunk::from(Path::new("/work/src/main.rs"));
+    assert_eq!(
+        serde_json::to_value(&chunk).unwrap(),
+        json!({ "type": "path", "path": "/work/src/main.rs" })
+    );
+
+    let range = Range::from(Position { line: 3, column: 1 }..Position { line: 7, column: 1 });
+    let chunk = UserMessageChunk::from((Path::new("/work/src/main.rs"), range));
+    assert_eq!(
+        serde_json::to_value(&chunk).unwrap(),
+        json!({
+            "type": "path",
+            "path": "/work/src/main.rs",
+            "range": {
+                "start": { "line": 3, "column": 1 },
+                "end": { "line": 7, "column": 1 },
+            },
+        })
+    );
+
+    let chunk: UserMessageChunk = serde_json::from_value(json!({
+        "type": "path",
+        "path": "/work/src/lib.rs",
+        "range": {
+            "start": { "line": 1, "column": 1 },
+            "end": { "line": 2, "column": 1 },
+        },
+        "content": "fn main() {}\n",
+    }))
+    .unwrap();
+    let UserMessageChunk::Path { range, content, .. } = chunk else {
+        panic!("expected a path chunk");
+    };
+    assert_eq!(range.unwrap().end.line, 2);
+    assert_eq!(content.as_deref(), Some("fn main() {}\n"));
+}
+
+#[test]
+fn test_resource_chunks() {
+    let chunk = UserMessageChunk::from(EmbeddedResource {
+        uri: "git://diff/HEAD".into(),
+        mime_type: Some("text/x-diff".into()),
+        text: Some("-old\n+new\n".into()),
+        blob: None,
+    });
+    chunk.validate().unwrap();
+    assert_eq!(
+        serde_json::to_value(&chunk).unwrap(),
+        json!({
+            "type": "resource",
+            "uri": "git://diff/HEAD",
+            "mimeType": "text/x-diff",
+            "text": "-old\n+new\n",
+        })
+    );
+
+    let chunk: UserMessageChunk = serde_json::from_value(json!({
+        "type": "resource",
+        "uri": "https://example.com/logo.png",
+        "mimeType": "image/png",
+        "blob": "iVBORw0KGgo=",
+    }))
+    .unwrap();
+    chunk.validate().unwrap();
+
+    let chunk = UserMessageChunk::from(EmbeddedResource {
+        uri: "diagnostics://workspace".into(),
+        mime_type: None,
+        text: None,
+        blob: None,
+    });
+    assert_eq!(chunk.validate().unwrap_err().code, Error::INVALID_PARAMS);
+}
+
+#[tokio::test]
+async fn test_tool_call_locations() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let client = TestClient::default();
+            let locations = client.locations.clone();
+            let (_client_connection, agent_connection) = connect(client, TestAgent::default());
+
+            let location = |path: &str, line| ToolCallLocation {
+                path: path.into(),
+                line,
+            };
+            let tool_call_id = agent_connection
+                .request(PushToolCallParams {
+                    thread_id: thread_id(),
+                    label: "Read main.rs".into(),
+                    icon: Icon::FileSearch,
+                    kind: ToolKind::Search,
+                    content: None,
+                    locations: vec![location("/work/src/main.rs", Some(12))],
+                })
+                .await
+                .unwrap()
+                .id;
+            assert_eq!(*locations.lock(), [location("/work/src/main.rs", Some(12))]);
+
+            // Updates without locations leave the previous ones in place.
+            agent_connection
+                .request(UpdateToolCallParams {
+                    thread_id: thread_id(),
+                    tool_call_id: tool_call_id.clone(),
+                    status: ToolCallStatus::Running,
+                    content: None,
+                    locations: None,
+                })
+                .await
+                .unwrap();
+            assert_eq!(locations.lock().len(), 1);
+
+            agent_connection
+                .request(UpdateToolCallParams {
+                    thread_id: thread_id(),
+                    tool_call_id,
+                    status: ToolCallStatus::Finished,
+                    content: None,
+                    locations: Some(vec![
+                        location("/work/src/lib.rs", None),
+                        location("/work/src/util.rs", Some(3)),
+                    ]),
+                })
+                .await
+                .unwrap();
+            assert_eq!(locations.lock().len(), 2);
+            assert_eq!(locations.lock()[0], location("/work/src/lib.rs", None));
+        })
+        .await
+}
+
+#[test]
+fn test_tool_kinds() {
+    let params = PushToolCallParams {
+        thread_id: thread_id(),
+        label: "Rename symbol".into(),
+        icon: Icon::Custom("rust-analyzer".into()),
+        kind: ToolKind::Edit,
+        content: None,
+        locations: Vec::new(),
+    };
+    assert_eq!(
+        serde_json::to_value(&params).unwrap(),
+        json!({
+            "threadId": "test-thread",
+            "label": "Rename symbol",
+            "icon": { "custom": "rust-analyzer" },
+            "kind": "edit",
+        })
+    );
+
+    // Agents that don't report a kind get the catch-all one.
+    let params: PushToolCallParams = serde_json::from_value(json!({
+        "threadId": "test-thread",
+        "label": "Search",
+        "icon": "fileSearch",
+    }))
+    .unwrap();
+    assert_eq!(params.kind, ToolKind::Other);
+}
+
+#[test]
+fn test_unknown_variants() {
+    let json = json!({
+        "threadId": "test-thread",
+        "chunk": { "type": "image", "mimeType": "image/png", "data": "iVBORw0KGgo=" },
+    });
+    let params: StreamAssistantMessageChunkParams = serde_json::from_value(json.clone()).unwrap();
+    assert!(matches!(params.chunk, AssistantMessageChunk::Unknown(_)));
+    assert_eq!(serde_json::to_value(&params).unwrap(), json);
+
+    let json = json!({
+        "threadId": "test-thread",
+        "label": "Deploy",
+        "icon": "rocket",
+        "kind": "execute",
+        "content": { "type": "video", "url": "https://example.com/deploy.mp4" },
+    });
+    let params: PushToolCallParams = serde_json::from_value(json.clone()).unwrap();
+    assert!(matches!(params.icon, Icon::Unknown(Value::String(ref icon)) if icon == "rocket"));
+    assert!(matches!(params.content, Some(ToolCallContent::Unknown(_))));
+    assert_eq!(serde_json::to_value(&params).unwrap(), json);
+
+    // Known variants still take precedence.
+    let params: StreamAssistantMessageChunkParams = serde_json::from_value(json!({
+        "threadId": "test-thread",
+        "chunk": { "type": "text", "chunk": "Hello" },
+    }))
+    .unwrap();
+    assert!(matches!(params.chunk, AssistantMessageChunk::Text { .. }));
+
+    // Malformed known variants are errors rather than unknown variants, and
+    // say what's wrong with them.
+    let error = serde_json::from_value::<AssistantMessageChunk>(json!({ "type": "text" }))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "missing field `chunk`");
+    let error = serde_json::from_value::<UserMessageChunk>(json!({ "type": "text" }))
+        .unwrap_err()
+        .to_string();
+    assert_eq!(error, "missing field `chunk`");
+    assert!(
+        serde_json::from_value::<ToolCallContent>(json!({ "type": "diff", "path": "/a" })).is_err()
+    );
+    assert!(serde_json::from_value::<Icon>(json!({ "custom": 5 })).is_err());
+
+    let kind: ToolKind = serde_json::from_value(json!("browse")).unwrap();
+    assert_eq!(kind, ToolKind::Unknown(json!("browse")));
+    assert_eq!(serde_json::to_value(&kind).unwrap(), json!("browse"));
+    let status: ToolCallStatus = serde_json::from_value(json!("waiting")).unwrap();
+    assert!(matches!(status, ToolCallStatus::Unknown(_)));
+    let outcome: ToolCallConfirmationOutcome = serde_json::from_value(json!("defer")).unwrap();
+    assert_eq!(
+        outcome,
+        ToolCallConfirmationOutcome::Unknown(json!("defer"))
+    );
+    let entry: PlanEntry = serde_json::from_value(json!({
+        "content": "Ship it",
+        "priority": "urgent",
+        "status": "blocked",
+    }))
+    .unwrap();
+    assert_eq!(entry.priority, PlanEntryPriority::Unknown(json!("urgent")));
+    assert_eq!(entry.status, PlanEntryStatus::Unknown(json!("blocked")));
+    let response: SendUserMessageResponse =
+        serde_json::from_value(json!({ "stopReason": "pause" })).unwrap();
+    assert_eq!(response.stop_reason, StopReason::Unknown(json!("pause")));
+    let confirmation: ToolCallConfirmation =
+        serde_json::from_value(json!({ "type": "browse", "url": "https://example.com" })).unwrap();
+    assert!(matches!(confirmation, ToolCallConfirmation::Unknown(_)));
+    assert!(serde_json::from_value::<StopReason>(json!(5)).is_err());
+}
+
+#[tokio::test]
+async fn test_unknown_user_message_chunks() {
+    env_logger::try_init().ok();
+
+    let local = LocalSet::new();
+    local
+        .run_until(async move {
+            let agent = TestAgent::default();
+            let messages_started = agent.messages_started.clone();
+            let (_agent_connection, mut writer, mut reader) = connect_raw_client(agent);
+
+            // A malformed chunk of a known type is rejected.
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":1,"method":"sendUserMessage","params":{"threadId":"test-thread","message":{"chunks":[{"type":"text"}]}}}"#,
+            )
+            .await;
+            let response = recv_json(&mut reader).await;
+            assert_eq!(response["error"]["code"], Error::INVALID_PARAMS);
+
+            // Chunks from a newer protocol version still reach the agent.
+            send_line(
+                &mut writer,
+                r#"{"jsonrpc":"2.0","id":2,"method":"sendUserMessage","params":{"threadId":"test-thread","message":{"chunks":[{"type":"audio","data":"AAAA"}]}}}"#,
+            )
+            .await;
+            wait_until(|| *messages_started.lock() == 1).await;
         })
         .await
 }
@@ -8,8 +8,10 @@ use std::fs;
 enum Message {
     ClientRequest(acp::AnyClientRequest),
     ClientResult(acp::AnyClientResult),
+    ClientNotification(acp::AnyClientNotification),
     AgentRequest(acp::AnyAgentRequest),
     AgentResult(acp::AnyAgentResult),
+    AgentNotification(acp::AnyAgentNotification),
 }
 
 fn main() {
@@ -36,4 +38,14 @@ fn main() {
         serde_json::to_string_pretty(&acp::AGENT_METHODS).unwrap(),
     )
     .unwrap();
+    fs::write(
+        "./target/client_notifications.json",
+        serde_json::to_string_pretty(&acp::CLIENT_NOTIFICATIONS).unwrap(),
+    )
+    .unwrap();
+    fs::write(
+        "./target/agent_notifications.json",
+        serde_json::to_string_pretty(&acp::AGENT_NOTIFICATIONS).unwrap(),
+    )
+    .unwrap();
 }
@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    pin::{Pin, pin},
+    process::ExitStatus,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_process::{Child, ChildStdin, Command, Stdio};
+use futures::{
+    AsyncBufReadExt as _, AsyncWrite, StreamExt as _,
+    future::{self, Either},
+    io::BufReader,
+};
+use futures_timer::Delay;
+use parking_lot::Mutex;
+
+use crate::{AgentConnection, Client, ConnectionState, MaybeSendSync};
+
+/// How to launch an agent process.
+#[derive(Debug, Clone, Default)]
+pub struct AgentCommand {
+    pub path: PathBuf,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub cwd: Option<PathBuf>,
+}
+
+/// An agent running as a child process, connected over its stdin and stdout.
+///
+/// The process is killed if this is dropped without calling [`Self::shutdown`].
+pub struct AgentProcess {
+    connection: AgentConnection,
+    child: Child,
+    stdin: Arc<Mutex<Option<ChildStdin>>>,
+}
+
+impl AgentProcess {
+    /// Spawn an agent process and connect to it, handling any incoming
+    /// requests using the given handler.
+    ///
+    /// As with [`AgentConnection::connect_to_agent`], the returned handler
+    /// and io futures must be polled for the connection to make progress.
+    /// The last future forwards the lines the agent writes to stderr to
+    /// `log`. It finishes when the agent's stderr is closed, which can
+    /// happen after the connection ends, e.g. if a process spawned by the
+    /// agent inherited it.
+    pub fn spawn<H: 'static + Client + MaybeSendSync>(
+        command: AgentCommand,
+        handler: H,
+    ) -> io::Result<(
+        Self,
+        impl Future<Output = ()>,
+        impl Future<Output = Result<()>>,
+        impl Future<Output = ()>,
+    )> {
+        let mut child = Command::new(&command.path);
+        child
+            .args(&command.args)
+            .envs(&command.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        if let Some(cwd) = &command.cwd {
+            child.current_dir(cwd);
+        }
+        let mut child = child.spawn()?;
+
+        let stdin = Arc::new(Mutex::new(child.stdin.take()));
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        let (connection, handler_task, io_task) =
+            AgentConnection::connect_to_agent(handler, ClosableStdin(stdin.clone()), stdout);
+
+        let name = command.path.display().to_string();
+        let stderr_task = async move {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Some(line) = lines.next().await {
+                match line {
+                    Ok(line) => log::info!("{name} stderr: {line}"),
+                    Err(error) => {
+                        log::warn!("failed to read {name} stderr: {error}");
+                        break;
+                    }
+                }
+            }
+        };
+        Ok((
+            Self {
+                connection,
+                child,
+                stdin,
+            },
+            handler_task,
+            io_task,
+            stderr_task,
+        ))
+    }
+
+    pub fn connection(&self) -> &AgentConnection {
+        &self.connection
+    }
+
+    /// The OS-assigned process identifier of the agent.
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Returns the agent's exit status if it has exited, without waiting.
+    pub fn try_status(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.child.try_status()
+    }
+
+    /// Wait for the agent to exit.
+    pub async fn status(&mut self) -> io::Result<ExitStatus> {
+        self.child.status().await
+    }
+
+    /// Close the connection, writing any queued messages, then close the
+    /// agent's stdin and wait for it to exit. The agent is killed if it is
+    /// still running after `grace_period`.
+    pub async fn shutdown(mut self, grace_period: Duration) -> io::Result<ExitStatus> {
+        let mut deadline = Delay::new(grace_period);
+
+        self.connection.close();
+        let closed = async {
+            let mut states = self.connection.watch_state();
+            while let Some(state) = states.next().await {
+                if matches!(state, ConnectionState::Closed(_)) {
+                    break;
+                }
+            }
+        };
+        future::select(pin!(closed), &mut deadline).await;
+        // The io future closes stdin itself, unless it is no longer polled.
+        self.stdin.lock().take();
+
+        let status = {
+            let status = pin!(self.child.status());
+            match future::select(status, &mut deadline).await {
+                Either::Left((status, _)) => Some(status),
+                Either::Right(_) => None,
+            }
+        };
+        if let Some(status) = status {
+            return status;
+        }
+
+        log::warn!("agent did not exit within {grace_period:?}, killing it");
+        self.child.kill()?;
+        self.child.status().await
+    }
+}
+
+/// The agent's stdin, which can be closed while the connection still owns it.
+struct ClosableStdin(Arc<Mutex<Option<ChildStdin>>>);
+
+impl AsyncWrite for ClosableStdin {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.0.lock().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_write(cx, buf),
+            None => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.0.lock().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.0.lock().as_mut() {
+            Some(stdin) => Pin::new(stdin).poll_close(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
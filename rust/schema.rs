@@ -1,12 +1,17 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{Result, anyhow};
+use anyhow::Result;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 
+use crate::HandlerContext;
+
 #[derive(Serialize)]
 pub struct Method {
     pub name: &'static str,
@@ -14,44 +19,103 @@ pub struct Method {
     pub response_type: &'static str,
 }
 
+#[derive(Serialize)]
+pub struct Notification {
+    pub name: &'static str,
+    pub params_type: &'static str,
+}
+
 pub trait AnyRequest: Serialize + Sized {
     type Response: Serialize;
-    fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self>;
-    fn response_from_method_and_result(method: &str, params: &RawValue) -> Result<Self::Response>;
+    type Notification: AnyNotification;
+    fn method_name(&self) -> &'static str;
+    fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self, crate::Error>;
+    fn response_from_method_and_result(
+        method: &str,
+        params: &RawValue,
+    ) -> Result<Self::Response, crate::Error>;
+}
+
+pub trait AnyNotification: Serialize + Sized {
+    fn method_name(&self) -> &'static str;
+    fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self, crate::Error>;
 }
 
 macro_rules! acp_peer {
     (
         $handler_trait_name:ident,
+        $peer_connection_name:ident,
         $request_trait_name:ident,
         $request_enum_name:ident,
         $response_enum_name:ident,
         $method_map_name:ident,
-        $(($request_method:ident, $request_method_string:expr, $request_name:ident, $response_name:ident)),*
+        $notification_trait_name:ident,
+        $notification_enum_name:ident,
+        $notification_map_name:ident,
+        requests: [
+            $(($request_method:ident, $request_method_string:expr, $request_name:ident, $response_name:ident)),*
+            $(,)?
+        ],
+        notifications: [
+            $(($notification_method:ident, $notification_method_string:expr, $notification_name:ident)),*
+            $(,)?
+        ]
         $(,)?
     ) => {
-        #[async_trait(?Send)]
+        #[cfg_attr(not(feature = "send"), async_trait(?Send))]
+        #[cfg_attr(feature = "send", async_trait)]
         pub trait $handler_trait_name {
-            async fn call(&self, params: $request_enum_name) -> Result<$response_enum_name> {
+            async fn call(
+                &self,
+                params: $request_enum_name,
+                cx: HandlerContext<crate::$peer_connection_name>,
+            ) -> Result<$response_enum_name> {
                 match params {
                     $($request_enum_name::$request_name(params) => {
-                        let response = self.$request_method(params).await?;
+                        let response = self.$request_method(params, cx).await?;
                         Ok($response_enum_name::$response_name(response))
                     }),*
                 }
             }
 
+            async fn call_notification(
+                &self,
+                params: $notification_enum_name,
+                cx: HandlerContext<crate::$peer_connection_name>,
+            ) -> Result<()> {
+                match (params, cx) {
+                    $(($notification_enum_name::$notification_name(params), cx) => {
+                        self.$notification_method(params, cx).await
+                    }),*
+                }
+            }
+
             $(
-                async fn $request_method(&self, request: $request_name) -> Result<$response_name>;
+                async fn $request_method(
+                    &self,
+                    request: $request_name,
+                    cx: HandlerContext<crate::$peer_connection_name>,
+                ) -> Result<$response_name>;
+            )*
+
+            $(
+                async fn $notification_method(
+                    &self,
+                    notification: $notification_name,
+                    cx: HandlerContext<crate::$peer_connection_name>,
+                ) -> Result<()>;
             )*
         }
 
         pub trait $request_trait_name {
             type Response;
+            const METHOD_NAME: &'static str;
             fn into_any(self) -> $request_enum_name;
             fn response_from_any(any: $response_enum_name) -> Option<Self::Response>;
         }
 
+        pub trait $notification_trait_name: Into<$notification_enum_name> {}
+
         #[derive(Serialize, JsonSchema)]
         #[serde(untagged)]
         pub enum $request_enum_name {
@@ -68,46 +132,77 @@ macro_rules! acp_peer {
             )*
         }
 
+        #[derive(Serialize, JsonSchema)]
+        #[serde(untagged)]
+        pub enum $notification_enum_name {
+            $(
+                $notification_name($notification_name),
+            )*
+        }
+
         impl AnyRequest for $request_enum_name {
             type Response = $response_enum_name;
+            type Notification = $notification_enum_name;
+
+            fn method_name(&self) -> &'static str {
+                match self {
+                    $(
+                        $request_enum_name::$request_name(_) => $request_method_string,
+                    )*
+                }
+            }
 
-            fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self> {
+            fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self, crate::Error> {
                 match method {
                     $(
                         $request_method_string => {
                             match serde_json::from_str(params.get()) {
                                 Ok(params) => Ok($request_enum_name::$request_name(params)),
-                                Err(e) => Err(anyhow!(e.to_string())),
+                                Err(e) => Err(crate::Error::invalid_params(e)),
                             }
                         }
                     )*
-                    _ => Err(anyhow!("invalid method string {}", method)),
+                    _ => Err(crate::Error::method_not_found(method)),
                 }
             }
 
-            fn response_from_method_and_result(method: &str, params: &RawValue) -> Result<Self::Response> {
+            fn response_from_method_and_result(method: &str, params: &RawValue) -> Result<Self::Response, crate::Error> {
                 match method {
                     $(
                         $request_method_string => {
                             match serde_json::from_str(params.get()) {
                                 Ok(params) => Ok($response_enum_name::$response_name(params)),
-                                Err(e) => Err(anyhow!(e.to_string())),
+                                Err(e) => Err(crate::Error::parse_error(e)),
                             }
                         }
                     )*
-                    _ => Err(anyhow!("invalid method string {}", method)),
+                    _ => Err(crate::Error::method_not_found(method)),
                 }
             }
         }
 
-        impl $request_enum_name {
-            pub fn method_name(&self) -> &'static str {
-                match self {
+        impl AnyNotification for $notification_enum_name {
+            fn method_name(&self) -> &'static str {
+                match *self {
                     $(
-                        $request_enum_name::$request_name(_) => $request_method_string,
+                        $notification_enum_name::$notification_name(_) => $notification_method_string,
                     )*
                 }
             }
+
+            fn from_method_and_params(method: &str, params: &RawValue) -> Result<Self, crate::Error> {
+                match (method, params) {
+                    $(
+                        ($notification_method_string, params) => {
+                            match serde_json::from_str(params.get()) {
+                                Ok(params) => Ok($notification_enum_name::$notification_name(params)),
+                                Err(e) => Err(crate::Error::invalid_params(e)),
+                            }
+                        }
+                    )*
+                    _ => Err(crate::Error::method_not_found(method)),
+                }
+            }
         }
 
         pub static $method_map_name: &[Method] = &[
@@ -120,9 +215,19 @@ macro_rules! acp_peer {
             )*
         ];
 
+        pub static $notification_map_name: &[Notification] = &[
+            $(
+                Notification {
+                    name: $notification_method_string,
+                    params_type: stringify!($notification_name),
+                },
+            )*
+        ];
+
         $(
             impl $request_trait_name for $request_name {
                 type Response = $response_name;
+                const METHOD_NAME: &'static str = $request_method_string;
 
                 fn into_any(self) -> $request_enum_name {
                     $request_enum_name::$request_name(self)
@@ -136,83 +241,365 @@ macro_rules! acp_peer {
                 }
             }
         )*
+
+        $(
+            impl $notification_trait_name for $notification_name {}
+
+            impl From<$notification_name> for $notification_enum_name {
+                fn from(notification: $notification_name) -> Self {
+                    $notification_enum_name::$notification_name(notification)
+                }
+            }
+        )*
     };
 }
 
+/// Implements `Serialize` and `Deserialize` for an enum derived with
+/// `#[serde(remote = "Self")]`, deserializing variants that aren't in the
+/// list of known ones as `Unknown` so peers on newer protocol versions don't
+/// break older ones. Malformed known variants are still errors.
+///
+/// The `Unknown` variant must be marked `#[serde(skip)]`, so that it doesn't
+/// affect the derived impls. It is serialized as the value it was read from.
+macro_rules! forward_compatible {
+    ($name:ident, $tag:expr, [$($known:literal),* $(,)?]) => {
+        impl Serialize for $name {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                match self {
+                    $name::Unknown(value) => value.serialize(serializer),
+                    _ => $name::serialize(self, serializer),
+                }
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                let value = serde_json::Value::deserialize(deserializer)?;
+                if is_unknown_variant(&value, $tag, &[$($known),*]) {
+                    return Ok($name::Unknown(value));
+                }
+                $name::deserialize(value).map_err(serde::de::Error::custom)
+            }
+        }
+    };
+}
+
+/// Whether `value` names a variant outside of `known`, either in its `tag`
+/// field or, for externally tagged enums, as a string or single-key object.
+fn is_unknown_variant(value: &serde_json::Value, tag: Option<&str>, known: &[&str]) -> bool {
+    let variant = match (tag, value) {
+        (Some(tag), value) => value.get(tag).and_then(serde_json::Value::as_str),
+        (None, serde_json::Value::String(variant)) => Some(variant.as_str()),
+        (None, serde_json::Value::Object(map)) if map.len() == 1 => {
+            map.keys().next().map(String::as_str)
+        }
+        (None, _) => None,
+    };
+    variant.is_some_and(|variant| !known.contains(&variant))
+}
+
 acp_peer!(
     Client,
+    AgentConnection,
     ClientRequest,
     AnyClientRequest,
     AnyClientResult,
     CLIENT_METHODS,
-    (
-        stream_assistant_message_chunk,
-        "streamAssistantMessageChunk",
-        StreamAssistantMessageChunkParams,
-        StreamAssistantMessageChunkResponse
-    ),
-    (
-        request_tool_call_confirmation,
-        "requestToolCallConfirmation",
-        RequestToolCallConfirmationParams,
-        RequestToolCallConfirmationResponse
-    ),
-    (
-        push_tool_call,
-        "pushToolCall",
-        PushToolCallParams,
-        PushToolCallResponse
-    ),
-    (
-        update_tool_call,
-        "updateToolCall",
-        UpdateToolCallParams,
-        UpdateToolCallResponse
-    ),
+    ClientNotification,
+    AnyClientNotification,
+    CLIENT_NOTIFICATIONS,
+    requests: [
+        (
+            request_tool_call_confirmation,
+            "requestToolCallConfirmation",
+            RequestToolCallConfirmationParams,
+            RequestToolCallConfirmationResponse
+        ),
+        (
+            push_tool_call,
+            "pushToolCall",
+            PushToolCallParams,
+            PushToolCallResponse
+        ),
+        (
+            update_tool_call,
+            "updateToolCall",
+            UpdateToolCallParams,
+            UpdateToolCallResponse
+        ),
+        (
+            update_plan,
+            "updatePlan",
+            UpdatePlanParams,
+            UpdatePlanResponse
+        ),
+        (
+            read_text_file,
+            "readTextFile",
+            ReadTextFileParams,
+            ReadTextFileResponse
+        ),
+        (
+            write_text_file,
+            "writeTextFile",
+            WriteTextFileParams,
+            WriteTextFileResponse
+        ),
+        (
+            create_terminal,
+            "createTerminal",
+            CreateTerminalParams,
+            CreateTerminalResponse
+        ),
+        (
+            terminal_output,
+            "terminalOutput",
+            TerminalOutputParams,
+            TerminalOutputResponse
+        ),
+        (
+            wait_for_terminal_exit,
+            "waitForTerminalExit",
+            WaitForTerminalExitParams,
+            WaitForTerminalExitResponse
+        ),
+        (
+            kill_terminal,
+            "killTerminal",
+            KillTerminalParams,
+            KillTerminalResponse
+        ),
+        (
+            release_terminal,
+            "releaseTerminal",
+            ReleaseTerminalParams,
+            ReleaseTerminalResponse
+        ),
+    ],
+    notifications: [
+        (
+            stream_assistant_message_chunk,
+            "streamAssistantMessageChunk",
+            StreamAssistantMessageChunkParams
+        ),
+        (
+            stream_user_message_chunk,
+            "streamUserMessageChunk",
+            StreamUserMessageChunkParams
+        ),
+    ],
 );
 
 acp_peer!(
     Agent,
+    ClientConnection,
     AgentRequest,
     AnyAgentRequest,
     AnyAgentResult,
     AGENT_METHODS,
-    (
-        initialize,
-        "initialize",
-        InitializeParams,
-        InitializeResponse
-    ),
-    (
-        authenticate,
-        "authenticate",
-        AuthenticateParams,
-        AuthenticateResponse
-    ),
-    (
-        send_user_message,
-        "sendUserMessage",
-        SendUserMessageParams,
-        SendUserMessageResponse
-    ),
-    (
-        cancel_send_message,
-        "cancelSendMessage",
-        CancelSendMessageParams,
-        CancelSendMessageResponse
-    )
+    AgentNotification,
+    AnyAgentNotification,
+    AGENT_NOTIFICATIONS,
+    requests: [
+        (
+            initialize,
+            "initialize",
+            InitializeParams,
+            InitializeResponse
+        ),
+        (
+            authenticate,
+            "authenticate",
+            AuthenticateParams,
+            AuthenticateResponse
+        ),
+        (
+            new_thread,
+            "newThread",
+            NewThreadParams,
+            NewThreadResponse
+        ),
+        (
+            list_threads,
+            "listThreads",
+            ListThreadsParams,
+            ListThreadsResponse
+        ),
+        (
+            open_thread,
+            "openThread",
+            OpenThreadParams,
+            OpenThreadResponse
+        ),
+        (
+            close_thread,
+            "closeThread",
+            CloseThreadParams,
+            CloseThreadResponse
+        ),
+        (
+            send_user_message,
+            "sendUserMessage",
+            SendUserMessageParams,
+            SendUserMessageResponse
+        ),
+        (
+            cancel_send_message,
+            "cancelSendMessage",
+            CancelSendMessageParams,
+            CancelSendMessageResponse
+        ),
+    ],
+    notifications: [
+        (
+            update_workspace_roots,
+            "updateWorkspaceRoots",
+            UpdateWorkspaceRootsParams
+        ),
+    ],
 );
 
-#[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct InitializeParams;
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+pub struct InitializeParams {
+    #[serde(default = "ProtocolVersion::v0")]
+    pub protocol_version: ProtocolVersion,
+    #[serde(default)]
+    pub client_capabilities: ClientCapabilities,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_info: Option<ClientInfo>,
+    #[serde(default)]
+    pub workspace_roots: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct InitializeResponse {
+    #[serde(default = "ProtocolVersion::v0")]
+    pub protocol_version: ProtocolVersion,
+    #[serde(default)]
+    pub agent_capabilities: AgentCapabilities,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_info: Option<AgentInfo>,
     pub is_authenticated: bool,
 }
 
+impl Serialize for InitializeParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        InitializeParams::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for InitializeParams {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Clients that predate version negotiation send no params at all.
+        let value = serde_json::Value::deserialize(deserializer)?;
+        if value.is_null() {
+            return Ok(Self {
+                protocol_version: ProtocolVersion::V0,
+                ..Default::default()
+            });
+        }
+        InitializeParams::deserialize(value).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientInfo {
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AgentInfo {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub homepage: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+#[derive(
+    Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(transparent)]
+pub struct ProtocolVersion(pub u16);
+
+impl ProtocolVersion {
+    /// The version of the protocol implemented by this crate.
+    pub const LATEST: Self = Self(1);
+
+    /// The oldest version this crate can still talk to.
+    pub const MIN_SUPPORTED: Self = Self(1);
+
+    /// The version assumed for peers that predate version negotiation and
+    /// don't send one.
+    pub const V0: Self = Self(0);
+
+    fn v0() -> Self {
+        Self::V0
+    }
+
+    /// Fails with a descriptive error unless a peer speaking this version
+    /// can talk to this crate.
+    pub fn ensure_supported(self) -> Result<(), crate::Error> {
+        if (Self::MIN_SUPPORTED..=Self::LATEST).contains(&self) {
+            Ok(())
+        } else {
+            Err(crate::Error::unsupported_protocol_version(self))
+        }
+    }
+
+    /// The version to use with a peer that asked for this one in
+    /// `initialize`: the older of the two, which the agent sends back in its
+    /// response. Fails if that version is no longer supported.
+    pub fn negotiate(self) -> Result<Self, crate::Error> {
+        let version = self.min(Self::LATEST);
+        version.ensure_supported()?;
+        Ok(version)
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::LATEST
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ClientCapabilities {
+    pub diffs: bool,
+    pub thoughts: bool,
+    pub fs: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default, rename_all = "camelCase")]
+pub struct AgentCapabilities {
+    pub cancellation: bool,
+    pub images: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateWorkspaceRootsParams {
+    pub workspace_roots: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthenticateParams;
@@ -227,6 +614,12 @@ pub struct UserMessage {
     pub chunks: Vec<UserMessageChunk>,
 }
 
+impl UserMessage {
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        self.chunks.iter().try_for_each(UserMessageChunk::validate)
+    }
+}
+
 impl<T> From<T> for UserMessage
 where
     T: Into<UserMessageChunk>,
@@ -239,10 +632,103 @@ where
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(tag = "type", rename_all = "camelCase")]
+#[serde(remote = "Self", tag = "type", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum UserMessageChunk {
-    Text { chunk: String },
-    Path { path: PathBuf },
+    Text {
+        chunk: String,
+    },
+    Path {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        range: Option<Range>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<String>,
+    },
+    #[serde(rename_all = "camelCase")]
+    Image {
+        mime_type: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<PathBuf>,
+    },
+    Resource {
+        #[serde(flatten)]
+        resource: EmbeddedResource,
+    },
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(
+    UserMessageChunk,
+    Some("type"),
+    ["text", "path", "image", "resource"]
+);
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmbeddedResource {
+    pub uri: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
+}
+
+impl UserMessageChunk {
+    /// The largest image, in decoded bytes, that can be sent inline.
+    pub const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+
+    /// Fails with an invalid params error if this chunk can't be sent, such
+    /// as an image that is too large or has neither data nor a path.
+    pub fn validate(&self) -> Result<(), crate::Error> {
+        let (mime_type, data, path) = match self {
+            Self::Image {
+                mime_type,
+                data,
+                path,
+            } => (mime_type, data, path),
+            Self::Resource { resource } => {
+                return if resource.text.is_some() != resource.blob.is_some() {
+                    Ok(())
+                } else {
+                    Err(crate::Error::invalid_params(format!(
+                        "resource {} must have exactly one of text or blob",
+                        resource.uri
+                    )))
+                };
+            }
+            _ => return Ok(()),
+        };
+
+        if !mime_type.starts_with("image/") {
+            return Err(crate::Error::invalid_params(format!(
+                "{mime_type} is not an image mime type"
+            )));
+        }
+        match (data, path) {
+            (Some(data), None) => {
+                // Every 4 base64 characters encode 3 bytes.
+                let size = data.len() / 4 * 3;
+                if size > Self::MAX_IMAGE_SIZE {
+                    return Err(crate::Error::invalid_params(format!(
+                        "image is {size} bytes, which exceeds the limit of {} bytes",
+                        Self::MAX_IMAGE_SIZE
+                    )));
+                }
+                Ok(())
+            }
+            (None, Some(_)) => Ok(()),
+            _ => Err(crate::Error::invalid_params(
+                "image must have exactly one of data or path",
+            )),
+        }
+    }
 }
 
 impl From<&str> for UserMessageChunk {
@@ -269,26 +755,148 @@ impl From<String> for UserMessageChunk {
 
 impl From<PathBuf> for UserMessageChunk {
     fn from(value: PathBuf) -> Self {
-        Self::Path { path: value }
+        Self::Path {
+            path: value,
+            range: None,
+            content: None,
+        }
     }
 }
 
 impl From<&Path> for UserMessageChunk {
     fn from(value: &Path) -> Self {
-        Self::Path { path: value.into() }
+        Self::from(value.to_path_buf())
+    }
+}
+
+impl From<(PathBuf, Range)> for UserMessageChunk {
+    fn from((path, range): (PathBuf, Range)) -> Self {
+        Self::Path {
+            path,
+            range: Some(range),
+            content: None,
+        }
+    }
+}
+
+impl From<(&Path, Range)> for UserMessageChunk {
+    fn from((path, range): (&Path, Range)) -> Self {
+        Self::from((path.to_path_buf(), range))
+    }
+}
+
+impl From<EmbeddedResource> for UserMessageChunk {
+    fn from(value: EmbeddedResource) -> Self {
+        Self::Resource { resource: value }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Range {
+    pub start: Position,
+    /// The position just past the end of the range.
+    pub end: Position,
+}
+
+impl From<std::ops::Range<Position>> for Range {
+    fn from(value: std::ops::Range<Position>) -> Self {
+        Self {
+            start: value.start,
+            end: value.end,
+        }
     }
 }
 
+#[derive(
+    Clone, Copy, Debug, Serialize, Deserialize, JsonSchema, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    /// 1-based line number.
+    pub line: u32,
+    /// 1-based column, counted in Unicode code points.
+    pub column: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(tag = "type", rename_all = "camelCase")]
+#[serde(remote = "Self", tag = "type", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum AssistantMessageChunk {
-    Text { chunk: String },
-    Thought { chunk: String },
+    Text {
+        chunk: String,
+    },
+    Thought {
+        chunk: String,
+    },
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(AssistantMessageChunk, Some("type"), ["text", "thought"]);
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct ThreadId(pub String);
+
+impl std::fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NewThreadParams;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NewThreadResponse {
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ListThreadsParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ListThreadsResponse {
+    pub threads: Vec<ThreadMetadata>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenThreadParams {
+    pub thread_id: ThreadId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct OpenThreadResponse;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CloseThreadParams {
+    pub thread_id: ThreadId,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
+pub struct CloseThreadResponse;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 pub struct ThreadMetadata {
+    pub thread_id: ThreadId,
     pub title: String,
     pub modified_at: DateTime<Utc>,
 }
@@ -296,35 +904,70 @@ pub struct ThreadMetadata {
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct SendUserMessageParams {
+    pub thread_id: ThreadId,
     pub message: UserMessage,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct SendUserMessageResponse;
+pub struct SendUserMessageResponse {
+    pub stop_reason: StopReason,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum StopReason {
+    EndTurn,
+    Cancelled,
+    MaxTokens,
+    Refusal,
+    Error,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(
+    StopReason,
+    None,
+    ["endTurn", "cancelled", "maxTokens", "refusal", "error"]
+);
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct StreamAssistantMessageChunkParams {
+    pub thread_id: ThreadId,
     pub chunk: AssistantMessageChunk,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct StreamAssistantMessageChunkResponse;
+pub struct StreamUserMessageChunkParams {
+    pub thread_id: ThreadId,
+    pub chunk: UserMessageChunk,
+}
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct RequestToolCallConfirmationParams {
+    pub thread_id: ThreadId,
     pub label: String,
     pub icon: Icon,
+    #[serde(default)]
+    pub kind: ToolKind,
     pub confirmation: ToolCallConfirmation,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<ToolCallContent>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<ToolCallLocation>,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum Icon {
     FileSearch,
     Folder,
@@ -334,10 +977,58 @@ pub enum Icon {
     Pencil,
     Regex,
     Terminal,
+    Custom(String),
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(
+    Icon,
+    None,
+    [
+        "fileSearch",
+        "folder",
+        "globe",
+        "hammer",
+        "lightBulb",
+        "pencil",
+        "regex",
+        "terminal",
+        "custom",
+    ]
+);
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum ToolKind {
+    Read,
+    Edit,
+    Delete,
+    Move,
+    Search,
+    Execute,
+    Think,
+    Fetch,
+    #[default]
+    Other,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
 }
 
+forward_compatible!(
+    ToolKind,
+    None,
+    [
+        "read", "edit", "delete", "move", "search", "execute", "think", "fetch", "other"
+    ]
+);
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(tag = "type", rename_all = "camelCase")]
+#[serde(remote = "Self", tag = "type", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum ToolCallConfirmation {
     #[serde(rename_all = "camelCase")]
     Edit {
@@ -367,8 +1058,17 @@ pub enum ToolCallConfirmation {
     },
     #[serde(rename_all = "camelCase")]
     Other { description: String },
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
 }
 
+forward_compatible!(
+    ToolCallConfirmation,
+    Some("type"),
+    ["edit", "execute", "mcp", "fetch", "other"]
+);
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "type", rename_all = "camelCase")]
 pub struct RequestToolCallConfirmationResponse {
@@ -376,8 +1076,9 @@ pub struct RequestToolCallConfirmationResponse {
     pub outcome: ToolCallConfirmationOutcome,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
-#[serde(rename_all = "camelCase")]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum ToolCallConfirmationOutcome {
     Allow,
     AlwaysAllow,
@@ -385,15 +1086,36 @@ pub enum ToolCallConfirmationOutcome {
     AlwaysAllowTool,
     Reject,
     Cancel,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
 }
 
+forward_compatible!(
+    ToolCallConfirmationOutcome,
+    None,
+    [
+        "allow",
+        "alwaysAllow",
+        "alwaysAllowMcpServer",
+        "alwaysAllowTool",
+        "reject",
+        "cancel"
+    ]
+);
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct PushToolCallParams {
+    pub thread_id: ThreadId,
     pub label: String,
     pub icon: Icon,
+    #[serde(default)]
+    pub kind: ToolKind,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<ToolCallContent>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub locations: Vec<ToolCallLocation>,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
@@ -409,24 +1131,43 @@ pub struct ToolCallId(pub u64);
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct UpdateToolCallParams {
+    pub thread_id: ThreadId,
     pub tool_call_id: ToolCallId,
     pub status: ToolCallStatus,
     pub content: Option<ToolCallContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locations: Option<Vec<ToolCallLocation>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolCallLocation {
+    pub path: PathBuf,
+    /// 1-based line number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct UpdateToolCallResponse;
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(rename_all = "camelCase")]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum ToolCallStatus {
     Running,
     Finished,
     Error,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
 }
 
+forward_compatible!(ToolCallStatus, None, ["running", "finished", "error"]);
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
-#[serde(tag = "type", rename_all = "camelCase")]
+#[serde(remote = "Self", tag = "type", rename_all = "camelCase")]
+#[non_exhaustive]
 pub enum ToolCallContent {
     #[serde(rename_all = "camelCase")]
     Markdown { markdown: String },
@@ -435,8 +1176,19 @@ pub enum ToolCallContent {
         #[serde(flatten)]
         diff: Diff,
     },
+    #[serde(rename_all = "camelCase")]
+    Terminal { terminal_id: TerminalId },
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
 }
 
+forward_compatible!(
+    ToolCallContent,
+    Some("type"),
+    ["markdown", "diff", "terminal"]
+);
+
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct Diff {
@@ -447,7 +1199,179 @@ pub struct Diff {
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
-pub struct CancelSendMessageParams;
+pub struct UpdatePlanParams {
+    pub thread_id: ThreadId,
+    pub entries: Vec<PlanEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlanResponse;
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanEntry {
+    pub content: String,
+    pub priority: PlanEntryPriority,
+    pub status: PlanEntryStatus,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum PlanEntryPriority {
+    High,
+    Medium,
+    Low,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(PlanEntryPriority, None, ["high", "medium", "low"]);
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(remote = "Self", rename_all = "camelCase")]
+#[non_exhaustive]
+pub enum PlanEntryStatus {
+    Pending,
+    InProgress,
+    Completed,
+    #[serde(skip)]
+    #[schemars(skip)]
+    Unknown(serde_json::Value),
+}
+
+forward_compatible!(
+    PlanEntryStatus,
+    None,
+    ["pending", "inProgress", "completed"]
+);
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadTextFileParams {
+    pub thread_id: ThreadId,
+    pub path: PathBuf,
+    /// 1-based line number to start reading from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<u32>,
+    /// Maximum number of lines to read.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadTextFileResponse {
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteTextFileParams {
+    pub thread_id: ThreadId,
+    pub path: PathBuf,
+    pub content: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteTextFileResponse;
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Eq, PartialEq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct TerminalId(pub String);
+
+impl std::fmt::Display for TerminalId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTerminalParams {
+    pub thread_id: ThreadId,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTerminalResponse {
+    pub terminal_id: TerminalId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct TerminalExitStatus {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TerminalOutputParams {
+    pub thread_id: ThreadId,
+    pub terminal_id: TerminalId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TerminalOutputResponse {
+    pub output: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_status: Option<TerminalExitStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WaitForTerminalExitParams {
+    pub thread_id: ThreadId,
+    pub terminal_id: TerminalId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WaitForTerminalExitResponse {
+    pub exit_status: TerminalExitStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KillTerminalParams {
+    pub thread_id: ThreadId,
+    pub terminal_id: TerminalId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct KillTerminalResponse;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReleaseTerminalParams {
+    pub thread_id: ThreadId,
+    pub terminal_id: TerminalId,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReleaseTerminalResponse;
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CancelSendMessageParams {
+    pub thread_id: ThreadId,
+}
 
 #[derive(Debug, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "camelCase")]
@@ -0,0 +1,92 @@
+use std::path::{Component, Path, PathBuf};
+
+use crate::{Error, InitializeParams, UpdateWorkspaceRootsParams};
+
+/// The directories an agent is allowed to operate on, as declared by the
+/// client in `initialize` and `updateWorkspaceRoots`.
+///
+/// Paths are compared lexically, without touching the file system, so paths
+/// that don't exist yet can be resolved too. Symlinks are not followed.
+#[derive(Debug, Clone, Default)]
+pub struct WorkspaceRoots {
+    roots: Vec<PathBuf>,
+    cwd: Option<PathBuf>,
+}
+
+impl WorkspaceRoots {
+    pub fn new(roots: Vec<PathBuf>, cwd: Option<PathBuf>) -> Self {
+        Self {
+            roots: roots.iter().map(|root| normalize(root)).collect(),
+            cwd: cwd.as_deref().map(normalize),
+        }
+    }
+
+    pub fn from_initialize(params: &InitializeParams) -> Self {
+        Self::new(params.workspace_roots.clone(), params.cwd.clone())
+    }
+
+    /// Replace the roots and working directory with the ones from an
+    /// `updateWorkspaceRoots` notification.
+    pub fn update(&mut self, params: UpdateWorkspaceRootsParams) {
+        *self = Self::new(params.workspace_roots, params.cwd);
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// The directory relative paths are resolved against: the working
+    /// directory if the client declared one, otherwise the first root.
+    pub fn cwd(&self) -> Option<&Path> {
+        self.cwd
+            .as_deref()
+            .or(self.roots.first().map(PathBuf::as_path))
+    }
+
+    /// Whether `path` is inside one of the roots. `path` must be absolute.
+    pub fn contains(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.roots.iter().any(|root| path.starts_with(root))
+    }
+
+    /// Resolve a path received from the client to an absolute path inside
+    /// the workspace, failing with an invalid params error otherwise.
+    pub fn resolve(&self, path: impl AsRef<Path>) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        let path = if path.is_absolute() {
+            normalize(path)
+        } else {
+            let cwd = self.cwd().ok_or_else(|| {
+                Error::invalid_params(format!(
+                    "can't resolve relative path {} without a workspace root",
+                    path.display()
+                ))
+            })?;
+            normalize(&cwd.join(path))
+        };
+
+        if self.contains(&path) {
+            Ok(path)
+        } else {
+            Err(Error::invalid_params(format!(
+                "{} is outside of the workspace",
+                path.display()
+            )))
+        }
+    }
+}
+
+/// Remove `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
@@ -6,19 +6,45 @@ import {
   CancelSendMessageParams,
   CancelSendMessageResponse,
   Client,
+  CloseThreadParams,
+  CloseThreadResponse,
+  CreateTerminalParams,
+  CreateTerminalResponse,
   Connection,
   InitializeParams,
   InitializeResponse,
+  KillTerminalParams,
+  KillTerminalResponse,
+  LATEST_PROTOCOL_VERSION,
+  ListThreadsParams,
+  ListThreadsResponse,
+  NewThreadParams,
+  NewThreadResponse,
+  OpenThreadParams,
+  OpenThreadResponse,
   PushToolCallParams,
   PushToolCallResponse,
+  ReadTextFileParams,
+  ReadTextFileResponse,
+  ReleaseTerminalParams,
+  ReleaseTerminalResponse,
   RequestToolCallConfirmationParams,
   RequestToolCallConfirmationResponse,
   SendUserMessageParams,
   SendUserMessageResponse,
   StreamAssistantMessageChunkParams,
-  StreamAssistantMessageChunkResponse,
+  StreamUserMessageChunkParams,
+  TerminalOutputParams,
+  TerminalOutputResponse,
+  UpdatePlanParams,
+  UpdatePlanResponse,
   UpdateToolCallParams,
   UpdateToolCallResponse,
+  UpdateWorkspaceRootsParams,
+  WaitForTerminalExitParams,
+  WaitForTerminalExitResponse,
+  WriteTextFileParams,
+  WriteTextFileResponse,
 } from "./acp.js";
 
 describe("Connection", () => {
@@ -61,13 +87,21 @@ describe("Connection", () => {
     // Test error handling in client->agent direction
     await expect(
       clientConnection.pushToolCall({
+        threadId: "thread",
         label: "/missing.ts",
         icon: "fileSearch",
+        kind: "search",
       }),
     ).rejects.toThrow();
 
     // Test error handling in agent->client direction
-    await expect(agentConnection.initialize(null)).rejects.toThrow();
+    await expect(
+      agentConnection.initialize({
+        protocolVersion: LATEST_PROTOCOL_VERSION,
+        clientCapabilities: { diffs: false, thoughts: false, fs: false },
+        workspaceRoots: [],
+      }),
+    ).rejects.toThrow();
   });
 
   it("handles concurrent requests", async () => {
@@ -103,16 +137,22 @@ describe("Connection", () => {
     // Send multiple concurrent requests
     const promises = [
       clientConnection.pushToolCall({
+        threadId: "thread",
         label: "Tool Call 1",
         icon: "fileSearch",
+        kind: "search",
       }),
       clientConnection.pushToolCall({
+        threadId: "thread",
         label: "Tool Call 2",
         icon: "fileSearch",
+        kind: "search",
       }),
       clientConnection.pushToolCall({
+        threadId: "thread",
         label: "Tool Call 3",
         icon: "fileSearch",
+        kind: "search",
       }),
     ];
 
@@ -143,7 +183,11 @@ describe("Connection", () => {
     class TestAgent extends StubAgent {
       async initialize(_: InitializeParams): Promise<InitializeResponse> {
         messageLog.push("initialize called");
-        return { isAuthenticated: true };
+        return {
+          protocolVersion: LATEST_PROTOCOL_VERSION,
+          agentCapabilities: { cancellation: false, images: false },
+          isAuthenticated: true,
+        };
       }
     }
 
@@ -161,12 +205,19 @@ describe("Connection", () => {
     );
 
     // Send requests in specific order
-    await agentConnection.initialize!(null);
+    await agentConnection.initialize!({
+      protocolVersion: LATEST_PROTOCOL_VERSION,
+      clientCapabilities: { diffs: false, thoughts: false, fs: false },
+      workspaceRoots: ["/workspace"],
+    });
     let { id } = await clientConnection.pushToolCall({
+      threadId: "thread",
       icon: "folder",
+      kind: "read",
       label: "Folder",
     });
     await clientConnection.updateToolCall({
+      threadId: "thread",
       content: {
         type: "markdown",
         markdown: "Markdown",
@@ -182,6 +233,85 @@ describe("Connection", () => {
       "updateToolCall called",
     ]);
   });
+
+  it("handles notifications without responding", async () => {
+    const chunks: string[] = [];
+
+    class TestClient extends StubClient {
+      async streamAssistantMessageChunk({
+        chunk,
+      }: StreamAssistantMessageChunkParams): Promise<void> {
+        chunks.push(chunk.chunk);
+      }
+      async pushToolCall(_: PushToolCallParams): Promise<PushToolCallResponse> {
+        return { id: 0 };
+      }
+    }
+
+    Connection.clientToAgent(
+      (agent) => new TestClient(agent),
+      clientToAgent.writable,
+      agentToClient.readable,
+    );
+
+    const clientConnection = Connection.agentToClient(
+      (client) => new StubAgent(client),
+      agentToClient.writable,
+      clientToAgent.readable,
+    );
+
+    await clientConnection.streamAssistantMessageChunk({
+      threadId: "thread",
+      chunk: { type: "text", chunk: "Hello" },
+    });
+    await clientConnection.streamAssistantMessageChunk({
+      threadId: "thread",
+      chunk: { type: "thought", chunk: "Thinking" },
+    });
+
+    // Notifications get no response, so round-trip a request to make sure
+    // everything sent before it has been handled.
+    await clientConnection.pushToolCall({
+      threadId: "thread",
+      label: "Tool Call",
+      icon: "fileSearch",
+      kind: "search",
+    });
+
+    expect(chunks).toEqual(["Hello", "Thinking"]);
+  });
+
+  it("rejects responses that aren't JSON-RPC 2.0", async () => {
+    const agentConnection = Connection.clientToAgent(
+      (agent) => new StubClient(agent),
+      clientToAgent.writable,
+      agentToClient.readable,
+    );
+
+    const response = agentConnection.initialize({
+      protocolVersion: LATEST_PROTOCOL_VERSION,
+      clientCapabilities: { diffs: false, thoughts: false, fs: false },
+      workspaceRoots: [],
+    });
+
+    const reader = clientToAgent.readable.getReader();
+    const { value } = await reader.read();
+    const request = JSON.parse(new TextDecoder().decode(value));
+    const writer = agentToClient.writable.getWriter();
+    await writer.write(
+      new TextEncoder().encode(
+        JSON.stringify({
+          id: request.id,
+          result: {
+            protocolVersion: LATEST_PROTOCOL_VERSION,
+            isAuthenticated: true,
+          },
+        }) + "\n",
+      ),
+    );
+
+    await expect(response).rejects.toMatchObject({ code: -32600 });
+  });
 });
 
 class StubAgent implements Agent {
@@ -192,6 +322,18 @@ class StubAgent implements Agent {
   authenticate(_: AuthenticateParams): Promise<AuthenticateResponse> {
     throw new Error("Method not implemented.");
   }
+  newThread(_: NewThreadParams): Promise<NewThreadResponse> {
+    throw new Error("Method not implemented.");
+  }
+  listThreads(_: ListThreadsParams): Promise<ListThreadsResponse> {
+    throw new Error("Method not implemented.");
+  }
+  openThread(_: OpenThreadParams): Promise<OpenThreadResponse> {
+    throw new Error("Method not implemented.");
+  }
+  closeThread(_: CloseThreadParams): Promise<CloseThreadResponse> {
+    throw new Error("Method not implemented.");
+  }
   sendUserMessage(_: SendUserMessageParams): Promise<SendUserMessageResponse> {
     throw new Error("Method not implemented.");
   }
@@ -200,13 +342,19 @@ class StubAgent implements Agent {
   ): Promise<CancelSendMessageResponse> {
     throw new Error("Method not implemented.");
   }
+  updateWorkspaceRoots(_: UpdateWorkspaceRootsParams): Promise<void> {
+    throw new Error("Method not implemented.");
+  }
 }
 
 class StubClient implements Client {
   constructor(private agent: Agent) {}
   streamAssistantMessageChunk(
     _: StreamAssistantMessageChunkParams,
-  ): Promise<StreamAssistantMessageChunkResponse> {
+  ): Promise<void> {
+    throw new Error("Method not implemented.");
+  }
+  streamUserMessageChunk(_: StreamUserMessageChunkParams): Promise<void> {
     throw new Error("Method not implemented.");
   }
   requestToolCallConfirmation(
@@ -220,4 +368,30 @@ class StubClient implements Client {
   updateToolCall(_: UpdateToolCallParams): Promise<UpdateToolCallResponse> {
     throw new Error("Method not implemented.");
   }
+  updatePlan(_: UpdatePlanParams): Promise<UpdatePlanResponse> {
+    throw new Error("Method not implemented.");
+  }
+  readTextFile(_: ReadTextFileParams): Promise<ReadTextFileResponse> {
+    throw new Error("Method not implemented.");
+  }
+  writeTextFile(_: WriteTextFileParams): Promise<WriteTextFileResponse> {
+    throw new Error("Method not implemented.");
+  }
+  createTerminal(_: CreateTerminalParams): Promise<CreateTerminalResponse> {
+    throw new Error("Method not implemented.");
+  }
+  terminalOutput(_: TerminalOutputParams): Promise<TerminalOutputResponse> {
+    throw new Error("Method not implemented.");
+  }
+  waitForTerminalExit(
+    _: WaitForTerminalExitParams,
+  ): Promise<WaitForTerminalExitResponse> {
+    throw new Error("Method not implemented.");
+  }
+  killTerminal(_: KillTerminalParams): Promise<KillTerminalResponse> {
+    throw new Error("Method not implemented.");
+  }
+  releaseTerminal(_: ReleaseTerminalParams): Promise<ReleaseTerminalResponse> {
+    throw new Error("Method not implemented.");
+  }
 }
@@ -1,4 +1,11 @@
-import { Agent, AGENT_METHODS, Client, CLIENT_METHODS } from "./schema.js";
+import {
+  Agent,
+  AGENT_METHODS,
+  AGENT_NOTIFICATIONS,
+  Client,
+  CLIENT_METHODS,
+  CLIENT_NOTIFICATIONS,
+} from "./schema.js";
 
 export * from "./schema.js";
 
@@ -7,15 +14,22 @@ type PendingResponse = {
   reject: (error: unknown) => void;
 };
 
-type AnyMessage = AnyRequest | AnyResponse;
+type AnyMessage = AnyRequest | AnyNotification | AnyResponse;
+
+type RequestId = number | string;
 
 type AnyRequest = {
-  id: number;
+  id: RequestId;
+  method: string;
+  params: unknown;
+};
+
+type AnyNotification = {
   method: string;
   params: unknown;
 };
 
-type AnyResponse = { id: number } & Result<unknown>;
+type AnyResponse = { id: RequestId | null } & Result<unknown>;
 
 type Result<T> =
   | {
@@ -25,11 +39,19 @@ type Result<T> =
       error: {
         code: number;
         message: string;
+        data?: unknown;
       };
     };
 
+const JSONRPC_VERSION = "2.0";
+
+/**
+ * The protocol version implemented by this library, exchanged in `initialize`.
+ */
+export const LATEST_PROTOCOL_VERSION = 1;
+
 export class Connection<D, P> {
-  #pendingResponses: Map<number, PendingResponse> = new Map();
+  #pendingResponses: Map<RequestId, PendingResponse> = new Map();
   #nextRequestId: number = 0;
   #delegate: D;
   #delegateMethods: Set<string>;
@@ -41,6 +63,7 @@ export class Connection<D, P> {
     delegate: (peer: P) => D,
     delegateMethods: Set<string>,
     peerMethods: Set<string>,
+    peerNotifications: Set<string>,
     peerInput: WritableStream<Uint8Array>,
     peerOutput: ReadableStream<Uint8Array>,
   ) {
@@ -59,6 +82,12 @@ export class Connection<D, P> {
       };
     }
 
+    for (const methodName of peerNotifications) {
+      peer[methodName] = (params: unknown) => {
+        return this.#sendNotification(methodName, params);
+      };
+    }
+
     this.#delegate = delegate(this as unknown as P);
     this.#receive(peerOutput);
   }
@@ -70,8 +99,9 @@ export class Connection<D, P> {
   ): Agent {
     return new Connection<Client, Agent>(
       client,
-      CLIENT_METHODS,
+      new Set([...CLIENT_METHODS, ...CLIENT_NOTIFICATIONS]),
       AGENT_METHODS,
+      AGENT_NOTIFICATIONS,
       input,
       output,
     ) as unknown as Agent;
@@ -84,8 +114,9 @@ export class Connection<D, P> {
   ): Client {
     return new Connection<Agent, Client>(
       agent,
-      AGENT_METHODS,
+      new Set([...AGENT_METHODS, ...AGENT_NOTIFICATIONS]),
       CLIENT_METHODS,
+      CLIENT_NOTIFICATIONS,
       input,
       output,
     ) as unknown as Client;
@@ -104,6 +135,21 @@ export class Connection<D, P> {
 
         if (trimmedLine) {
           const message = JSON.parse(trimmedLine);
+          if (message.jsonrpc !== JSONRPC_VERSION) {
+            const error = {
+              code: -32600,
+              message: `Invalid request: expected "jsonrpc": "${JSONRPC_VERSION}"`,
+            };
+            if ("method" in message) {
+              if ("id" in message) {
+                this.#sendMessage({ id: message.id, error });
+              }
+            } else if ("id" in message) {
+              // Fail the pending request rather than leaving it hanging.
+              this.#handleResponse({ id: message.id, error });
+            }
+            continue;
+          }
           this.#processMessage(message);
         }
       }
@@ -111,7 +157,7 @@ export class Connection<D, P> {
   }
 
   async #processMessage(message: AnyMessage) {
-    if ("method" in message) {
+    if ("method" in message && "id" in message) {
       let response = await this.#tryCallDelegateMethod(
         message.method,
         message.params,
@@ -121,6 +167,21 @@ export class Connection<D, P> {
         id: message.id,
         ...response,
       });
+    } else if ("method" in message) {
+      if (message.method.startsWith("$/")) {
+        // Protocol notifications such as `$/cancelRequest` are optional.
+        return;
+      }
+      const response = await this.#tryCallDelegateMethod(
+        message.method,
+        message.params,
+      );
+      if ("error" in response) {
+        console.error(
+          `failed to handle ${message.method} notification:`,
+          response.error,
+        );
+      }
     } else {
       this.#handleResponse(message);
     }
@@ -146,6 +207,7 @@ export class Connection<D, P> {
     } catch (error: unknown) {
       let code = -32603;
       let errMessage = "Unknown Error";
+      let data: unknown = undefined;
 
       if (error && typeof error === "object") {
         if ("code" in error && typeof error.code === "number") {
@@ -154,15 +216,21 @@ export class Connection<D, P> {
         if ("message" in error && typeof error.message === "string") {
           errMessage = error.message;
         }
+        if ("data" in error) {
+          data = error.data;
+        }
       }
 
       return {
-        error: { code, message: errMessage },
+        error: { code, message: errMessage, data },
       };
     }
   }
 
   #handleResponse(response: AnyResponse) {
+    if (response.id === null) {
+      return;
+    }
     const pendingResponse = this.#pendingResponses.get(response.id);
     if (pendingResponse) {
       if ("result" in response) {
@@ -183,8 +251,13 @@ export class Connection<D, P> {
     return responsePromise;
   }
 
+  async #sendNotification(method: string, params: unknown): Promise<void> {
+    await this.#sendMessage({ method, params });
+  }
+
   async #sendMessage(json: AnyMessage) {
-    const content = JSON.stringify(json) + "\n";
+    const content =
+      JSON.stringify({ jsonrpc: JSONRPC_VERSION, ...json }) + "\n";
     this.#writeQueue = this.#writeQueue
       .then(async () => {
         const writer = this.#peerInput.getWriter();
@@ -13,25 +13,42 @@ const clientMethods = JSON.parse(
 const agentMethods = JSON.parse(
   fs.readFileSync("./target/agent_requests.json", "utf8"),
 );
+const clientNotifications = JSON.parse(
+  fs.readFileSync("./target/client_notifications.json", "utf8"),
+);
+const agentNotifications = JSON.parse(
+  fs.readFileSync("./target/agent_notifications.json", "utf8"),
+);
 
 let typescriptSource = await compile(jsonSchema, "Agent Coding Protocol", {
   additionalProperties: false,
   bannerComment: false,
 });
 
-const clientInterface = requestMapToInterface("Client", clientMethods);
-const agentInterface = requestMapToInterface("Agent", agentMethods);
+const clientInterface = requestMapToInterface(
+  "Client",
+  clientMethods,
+  clientNotifications,
+);
+const agentInterface = requestMapToInterface(
+  "Agent",
+  agentMethods,
+  agentNotifications,
+);
 
 typescriptSource += "\n" + clientInterface + "\n\n" + agentInterface + "\n";
 
 fs.writeFileSync("typescript/schema.ts", typescriptSource, "utf8");
 
-function requestMapToInterface(name, methods) {
+function requestMapToInterface(name, methods, notifications) {
   let code = `export interface ${name} {\n`;
 
   for (const { name, request_type, response_type } of methods) {
     code += `  ${name}(params: ${request_type}): Promise<${response_type}>;\n`;
   }
+  for (const { name, params_type } of notifications) {
+    code += `  ${name}(params: ${params_type}): Promise<void>;\n`;
+  }
   code += "}\n\n";
 
   code += `export const ${name.toUpperCase()}_METHODS = new Set([`;
@@ -40,6 +57,14 @@ function requestMapToInterface(name, methods) {
     code += `  "${name}",`;
     code += "\n";
   }
+  code += "]);\n\n";
+
+  code += `export const ${name.toUpperCase()}_NOTIFICATIONS = new Set([`;
+  code += "\n";
+  for (const { name } of notifications) {
+    code += `  "${name}",`;
+    code += "\n";
+  }
   code += "]);";
 
   return code;
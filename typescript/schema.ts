@@ -1,48 +1,48 @@
 export type AgentCodingProtocol =
   | AnyClientRequest
   | AnyClientResult
+  | AnyClientNotification
   | AnyAgentRequest
-  | AnyAgentResult;
+  | AnyAgentResult
+  | AnyAgentNotification;
 export type AnyClientRequest =
-  | StreamAssistantMessageChunkParams
   | RequestToolCallConfirmationParams
   | PushToolCallParams
-  | UpdateToolCallParams;
-export type AssistantMessageChunk =
-  | {
-      type: "text";
-      chunk: string;
-    }
-  | {
-      type: "thought";
-      chunk: string;
-    };
+  | UpdateToolCallParams
+  | UpdatePlanParams
+  | ReadTextFileParams
+  | WriteTextFileParams
+  | CreateTerminalParams
+  | TerminalOutputParams
+  | WaitForTerminalExitParams
+  | KillTerminalParams
+  | ReleaseTerminalParams;
 export type ToolCallConfirmation =
   | {
-      description?: string | null;
       type: "edit";
+      description?: string | null;
     }
   | {
-      description?: string | null;
       type: "execute";
       command: string;
+      description?: string | null;
       rootCommand: string;
     }
   | {
-      description?: string | null;
       type: "mcp";
+      description?: string | null;
       serverName: string;
       toolDisplayName: string;
       toolName: string;
     }
   | {
-      description?: string | null;
       type: "fetch";
+      description?: string | null;
       urls: string[];
     }
   | {
-      description: string;
       type: "other";
+      description: string;
     };
 export type ToolCallContent =
   | {
@@ -54,7 +54,12 @@ export type ToolCallContent =
       newText: string;
       oldText: string | null;
       path: string;
+    }
+  | {
+      type: "terminal";
+      terminalId: TerminalId;
     };
+export type TerminalId = string;
 export type Icon =
   | "fileSearch"
   | "folder"
@@ -63,15 +68,37 @@ export type Icon =
   | "lightBulb"
   | "pencil"
   | "regex"
-  | "terminal";
+  | "terminal"
+  | {
+      custom: string;
+    };
+export type ToolKind =
+  | "read"
+  | "edit"
+  | "delete"
+  | "move"
+  | "search"
+  | "execute"
+  | "think"
+  | "fetch"
+  | "other";
+export type ThreadId = string;
 export type ToolCallStatus = "running" | "finished" | "error";
 export type ToolCallId = number;
+export type PlanEntryPriority = "high" | "medium" | "low";
+export type PlanEntryStatus = "pending" | "inProgress" | "completed";
 export type AnyClientResult =
-  | StreamAssistantMessageChunkResponse
   | RequestToolCallConfirmationResponse
   | PushToolCallResponse
-  | UpdateToolCallResponse;
-export type StreamAssistantMessageChunkResponse = null;
+  | UpdateToolCallResponse
+  | UpdatePlanResponse
+  | ReadTextFileResponse
+  | WriteTextFileResponse
+  | CreateTerminalResponse
+  | TerminalOutputResponse
+  | WaitForTerminalExitResponse
+  | KillTerminalResponse
+  | ReleaseTerminalResponse;
 export type ToolCallConfirmationOutcome =
   | "allow"
   | "alwaysAllow"
@@ -80,13 +107,22 @@ export type ToolCallConfirmationOutcome =
   | "reject"
   | "cancel";
 export type UpdateToolCallResponse = null;
-export type AnyAgentRequest =
-  | InitializeParams
-  | AuthenticateParams
-  | SendUserMessageParams
-  | CancelSendMessageParams;
-export type InitializeParams = null;
-export type AuthenticateParams = null;
+export type UpdatePlanResponse = null;
+export type WriteTextFileResponse = null;
+export type KillTerminalResponse = null;
+export type ReleaseTerminalResponse = null;
+export type AnyClientNotification =
+  | StreamAssistantMessageChunkParams
+  | StreamUserMessageChunkParams;
+export type AssistantMessageChunk =
+  | {
+      type: "text";
+      chunk: string;
+    }
+  | {
+      type: "thought";
+      chunk: string;
+    };
 export type UserMessageChunk =
   | {
       type: "text";
@@ -94,37 +130,137 @@ export type UserMessageChunk =
     }
   | {
       type: "path";
+      content?: string | null;
       path: string;
+      range?: Range | null;
+    }
+  | {
+      type: "image";
+      data?: string | null;
+      mimeType: string;
+      path?: string | null;
+    }
+  | {
+      type: "resource";
+      blob?: string | null;
+      mimeType?: string | null;
+      text?: string | null;
+      uri: string;
     };
-export type CancelSendMessageParams = null;
+export type AnyAgentRequest =
+  | InitializeParams
+  | AuthenticateParams
+  | NewThreadParams
+  | ListThreadsParams
+  | OpenThreadParams
+  | CloseThreadParams
+  | SendUserMessageParams
+  | CancelSendMessageParams;
+export type AuthenticateParams = null;
+export type NewThreadParams = null;
 export type AnyAgentResult =
   | InitializeResponse
   | AuthenticateResponse
+  | NewThreadResponse
+  | ListThreadsResponse
+  | OpenThreadResponse
+  | CloseThreadResponse
   | SendUserMessageResponse
   | CancelSendMessageResponse;
 export type AuthenticateResponse = null;
-export type SendUserMessageResponse = null;
+export type OpenThreadResponse = null;
+export type CloseThreadResponse = null;
+export type StopReason =
+  | "endTurn"
+  | "cancelled"
+  | "maxTokens"
+  | "refusal"
+  | "error";
 export type CancelSendMessageResponse = null;
+export type AnyAgentNotification = UpdateWorkspaceRootsParams;
 
-export interface StreamAssistantMessageChunkParams {
-  chunk: AssistantMessageChunk;
-}
 export interface RequestToolCallConfirmationParams {
   confirmation: ToolCallConfirmation;
   content?: ToolCallContent | null;
   icon: Icon;
+  kind: ToolKind;
   label: string;
+  locations?: ToolCallLocation[];
+  threadId: ThreadId;
+}
+export interface ToolCallLocation {
+  /**
+   * 1-based line number.
+   */
+  line?: number | null;
+  path: string;
 }
 export interface PushToolCallParams {
   content?: ToolCallContent | null;
   icon: Icon;
+  kind: ToolKind;
   label: string;
+  locations?: ToolCallLocation[];
+  threadId: ThreadId;
 }
 export interface UpdateToolCallParams {
   content: ToolCallContent | null;
+  locations?: ToolCallLocation[] | null;
   status: ToolCallStatus;
+  threadId: ThreadId;
   toolCallId: ToolCallId;
 }
+export interface UpdatePlanParams {
+  entries: PlanEntry[];
+  threadId: ThreadId;
+}
+export interface PlanEntry {
+  content: string;
+  priority: PlanEntryPriority;
+  status: PlanEntryStatus;
+}
+export interface ReadTextFileParams {
+  /**
+   * Maximum number of lines to read.
+   */
+  limit?: number | null;
+  /**
+   * 1-based line number to start reading from.
+   */
+  line?: number | null;
+  path: string;
+  threadId: ThreadId;
+}
+export interface WriteTextFileParams {
+  content: string;
+  path: string;
+  threadId: ThreadId;
+}
+export interface CreateTerminalParams {
+  args: string[];
+  command: string;
+  cwd?: string | null;
+  env: {
+    [k: string]: string;
+  };
+  threadId: ThreadId;
+}
+export interface TerminalOutputParams {
+  terminalId: TerminalId;
+  threadId: ThreadId;
+}
+export interface WaitForTerminalExitParams {
+  terminalId: TerminalId;
+  threadId: ThreadId;
+}
+export interface KillTerminalParams {
+  terminalId: TerminalId;
+  threadId: ThreadId;
+}
+export interface ReleaseTerminalParams {
+  terminalId: TerminalId;
+  threadId: ThreadId;
+}
 export interface RequestToolCallConfirmationResponse {
   id: ToolCallId;
   outcome: ToolCallConfirmationOutcome;
@@ -132,48 +268,189 @@ export interface RequestToolCallConfirmationResponse {
 export interface PushToolCallResponse {
   id: ToolCallId;
 }
+export interface ReadTextFileResponse {
+  content: string;
+}
+export interface CreateTerminalResponse {
+  terminalId: TerminalId;
+}
+export interface TerminalOutputResponse {
+  exitStatus?: TerminalExitStatus | null;
+  output: string;
+}
+export interface TerminalExitStatus {
+  exitCode?: number | null;
+  signal?: string | null;
+}
+export interface WaitForTerminalExitResponse {
+  exitStatus: TerminalExitStatus;
+}
+export interface StreamAssistantMessageChunkParams {
+  chunk: AssistantMessageChunk;
+  threadId: ThreadId;
+}
+export interface StreamUserMessageChunkParams {
+  chunk: UserMessageChunk;
+  threadId: ThreadId;
+}
+export interface Range {
+  /**
+   * The position just past the end of the range.
+   */
+  end: Position;
+  start: Position;
+}
+export interface Position {
+  /**
+   * 1-based column, counted in Unicode code points.
+   */
+  column: number;
+  /**
+   * 1-based line number.
+   */
+  line: number;
+}
+export interface InitializeParams {
+  clientCapabilities: ClientCapabilities;
+  clientInfo?: ClientInfo | null;
+  cwd?: string | null;
+  protocolVersion: number;
+  workspaceRoots: string[];
+}
+export interface ClientCapabilities {
+  diffs: boolean;
+  fs: boolean;
+  thoughts: boolean;
+}
+export interface ClientInfo {
+  name: string;
+  version: string;
+}
+export interface ListThreadsParams {
+  cursor?: string | null;
+  limit?: number | null;
+}
+export interface OpenThreadParams {
+  threadId: ThreadId;
+}
+export interface CloseThreadParams {
+  threadId: ThreadId;
+}
 export interface SendUserMessageParams {
   message: UserMessage;
+  threadId: ThreadId;
 }
 export interface UserMessage {
   chunks: UserMessageChunk[];
 }
+export interface CancelSendMessageParams {
+  threadId: ThreadId;
+}
 export interface InitializeResponse {
+  agentCapabilities: AgentCapabilities;
+  agentInfo?: AgentInfo | null;
   isAuthenticated: boolean;
+  protocolVersion: number;
+}
+export interface AgentCapabilities {
+  cancellation: boolean;
+  images: boolean;
+}
+export interface AgentInfo {
+  description?: string | null;
+  homepage?: string | null;
+  name: string;
+  version: string;
+}
+export interface NewThreadResponse {
+  threadId: ThreadId;
+}
+export interface ListThreadsResponse {
+  nextCursor?: string | null;
+  threads: ThreadMetadata[];
+}
+export interface ThreadMetadata {
+  modifiedAt: string;
+  threadId: ThreadId;
+  title: string;
+}
+export interface SendUserMessageResponse {
+  detail?: string | null;
+  stopReason: StopReason;
+}
+export interface UpdateWorkspaceRootsParams {
+  cwd?: string | null;
+  workspaceRoots: string[];
 }
 
 export interface Client {
-  streamAssistantMessageChunk(
-    params: StreamAssistantMessageChunkParams,
-  ): Promise<StreamAssistantMessageChunkResponse>;
   requestToolCallConfirmation(
     params: RequestToolCallConfirmationParams,
   ): Promise<RequestToolCallConfirmationResponse>;
   pushToolCall(params: PushToolCallParams): Promise<PushToolCallResponse>;
   updateToolCall(params: UpdateToolCallParams): Promise<UpdateToolCallResponse>;
+  updatePlan(params: UpdatePlanParams): Promise<UpdatePlanResponse>;
+  readTextFile(params: ReadTextFileParams): Promise<ReadTextFileResponse>;
+  writeTextFile(params: WriteTextFileParams): Promise<WriteTextFileResponse>;
+  createTerminal(params: CreateTerminalParams): Promise<CreateTerminalResponse>;
+  terminalOutput(params: TerminalOutputParams): Promise<TerminalOutputResponse>;
+  waitForTerminalExit(
+    params: WaitForTerminalExitParams,
+  ): Promise<WaitForTerminalExitResponse>;
+  killTerminal(params: KillTerminalParams): Promise<KillTerminalResponse>;
+  releaseTerminal(
+    params: ReleaseTerminalParams,
+  ): Promise<ReleaseTerminalResponse>;
+  streamAssistantMessageChunk(
+    params: StreamAssistantMessageChunkParams,
+  ): Promise<void>;
+  streamUserMessageChunk(params: StreamUserMessageChunkParams): Promise<void>;
 }
 
 export const CLIENT_METHODS = new Set([
-  "streamAssistantMessageChunk",
   "requestToolCallConfirmation",
   "pushToolCall",
   "updateToolCall",
+  "updatePlan",
+  "readTextFile",
+  "writeTextFile",
+  "createTerminal",
+  "terminalOutput",
+  "waitForTerminalExit",
+  "killTerminal",
+  "releaseTerminal",
+]);
+
+export const CLIENT_NOTIFICATIONS = new Set([
+  "streamAssistantMessageChunk",
+  "streamUserMessageChunk",
 ]);
 
 export interface Agent {
   initialize(params: InitializeParams): Promise<InitializeResponse>;
   authenticate(params: AuthenticateParams): Promise<AuthenticateResponse>;
+  newThread(params: NewThreadParams): Promise<NewThreadResponse>;
+  listThreads(params: ListThreadsParams): Promise<ListThreadsResponse>;
+  openThread(params: OpenThreadParams): Promise<OpenThreadResponse>;
+  closeThread(params: CloseThreadParams): Promise<CloseThreadResponse>;
   sendUserMessage(
     params: SendUserMessageParams,
   ): Promise<SendUserMessageResponse>;
   cancelSendMessage(
     params: CancelSendMessageParams,
   ): Promise<CancelSendMessageResponse>;
+  updateWorkspaceRoots(params: UpdateWorkspaceRootsParams): Promise<void>;
 }
 
 export const AGENT_METHODS = new Set([
   "initialize",
   "authenticate",
+  "newThread",
+  "listThreads",
+  "openThread",
+  "closeThread",
   "sendUserMessage",
   "cancelSendMessage",
 ]);
+
+export const AGENT_NOTIFICATIONS = new Set(["updateWorkspaceRoots"]);